@@ -1,3 +1,6 @@
+use std::cell::Cell;
+use std::cmp::Ordering;
+use std::convert::TryFrom;
 use std::fs::File;
 use std::io::Read;
 use std::rc::Rc;
@@ -9,18 +12,68 @@ use rustyline::error::ReadlineError;
 use rustyline::Editor;
 
 use crate::printer::pr_seq;
-use crate::reader::read_str;
-use crate::types::MalErr::ErrMalVal;
-use crate::types::MalVal::{Atom, Bool, Func, Hash, Int, List, MalFunc, Nil, Str, Sym, Vector};
-use crate::types::{MalArgs, MalRet, MalVal, _assoc, _dissoc, atom, error, func, hash_map};
-
-macro_rules! fn_t_int_int {
-    ($ret:ident, $fn:expr) => {{
-        |a: MalArgs| match (&a[0], &a[1]) {
-            (Int(a0), Int(a1)) => Ok($ret($fn(a0, a1))),
-            _ => error("expecting (int,int) args"),
-        }
-    }};
+use crate::reader::{read_comments, read_str, tokenize_source};
+use crate::types::MalErr::{ErrString, Thrown, TypeError};
+use crate::types::MalVal::{
+    Atom, Bool, Float, Func, Hash, Int, List, MalFunc, Nil, Pattern, Set, Str, Sym, Vector,
+};
+use crate::types::{
+    MalArgs, MalErr, MalMap, MalRet, MalVal, _assoc, _conj_set, _dissoc, arity_error, atom,
+    check_arity, error, func, hash_map, hash_set, pattern, type_error,
+};
+
+fn as_float(v: &MalVal) -> Option<f64> {
+    match v {
+        Int(i) => Some(*i as f64),
+        Float(f) => Some(*f),
+        _ => None,
+    }
+}
+
+// Names the first argument that is not a number.
+fn num_args_error(a: &MalArgs) -> MalRet {
+    match a.iter().take(2).position(|v| as_float(v).is_none()) {
+        Some(i) => type_error(&format!(
+            "expecting (number,number) args, got {} as argument {}",
+            a[i].pr_str(true),
+            i + 1
+        )),
+        None => type_error("expecting (number,number) args"),
+    }
+}
+
+// Two integers give an exact integer, and one that would leave the
+// range of i64 is an error rather than a panic or a wrapped-around
+// result. A float on either side makes the result a float.
+fn num_op(
+    name: &str,
+    a: &MalArgs,
+    int_op: fn(i64, i64) -> Option<i64>,
+    float_op: fn(f64, f64) -> f64,
+) -> MalRet {
+    match (&a[0], &a[1]) {
+        (Int(a0), Int(a1)) => match int_op(*a0, *a1) {
+            Some(r) => Ok(Int(r)),
+            None => error(&format!("{}: integer overflow", name)),
+        },
+        _ => match (as_float(&a[0]), as_float(&a[1])) {
+            (Some(x), Some(y)) => Ok(Float(float_op(x, y))),
+            _ => num_args_error(a),
+        },
+    }
+}
+
+// Integers compare exactly; an integer and a float compare as floats.
+// Nothing is ordered against NaN, so every test with it is false.
+fn compare(a: &MalArgs, test: fn(Ordering) -> bool) -> MalRet {
+    let ord = match (&a[0], &a[1]) {
+        (Int(i), Int(j)) => Some(i.cmp(j)),
+        _ => match (as_float(&a[0]), as_float(&a[1])) {
+            (Some(x), Some(y)) => x.partial_cmp(&y),
+            _ => return num_args_error(a),
+        },
+    };
+    Ok(Bool(ord.is_some_and(test)))
 }
 
 macro_rules! fn_is_type {
@@ -39,18 +92,59 @@ macro_rules! fn_str {
     ($fn:expr) => {{
         |a: MalArgs| match &a[0] {
             Str(a0) => $fn(&a0),
-            _ => error("expecting (str) arg"),
+            _ => type_error("expecting (str) arg"),
         }
     }};
 }
 
+thread_local! {
+    // Symbols made by gensym so far.
+    static GENSYM_COUNT: Cell<i64> = const { Cell::new(0) };
+}
+
+// A symbol named prefix followed by a number no other gensym has used.
+pub fn gensym(prefix: &str) -> MalVal {
+    GENSYM_COUNT.with(|c| {
+        c.set(c.get() + 1);
+        Sym(format!("{}{}", prefix, c.get()))
+    })
+}
+
 fn symbol(a: MalArgs) -> MalRet {
     match a[0] {
         Str(ref s) => Ok(Sym(s.to_string())),
-        _ => error("illegal symbol call"),
+        _ => type_error("illegal symbol call"),
     }
 }
 
+// Splits "ns/name" at the first slash. A bare "/" is a name.
+fn split_ns(s: &str) -> (Option<&str>, &str) {
+    match s.find('/') {
+        Some(i) if i > 0 && i + 1 < s.len() => (Some(&s[..i]), &s[i + 1..]),
+        _ => (None, s),
+    }
+}
+
+fn name(a: MalArgs) -> MalRet {
+    match a[0] {
+        Str(ref s) => match s.strip_prefix('\u{29e}') {
+            Some(kw) => Ok(Str(split_ns(kw).1.to_string())),
+            None => Ok(Str(s.to_string())),
+        },
+        Sym(ref s) => Ok(Str(split_ns(s).1.to_string())),
+        _ => type_error("name: expected a keyword, symbol or string"),
+    }
+}
+
+fn namespace(a: MalArgs) -> MalRet {
+    let ns = match a[0] {
+        Str(ref s) if a[0].keyword_q() => split_ns(&s['\u{29e}'.len_utf8()..]).0,
+        Sym(ref s) => split_ns(s).0,
+        _ => return type_error("namespace: expected a keyword or symbol"),
+    };
+    Ok(ns.map_or(Nil, |ns| Str(ns.to_string())))
+}
+
 fn readline(a: MalArgs) -> MalRet {
     lazy_static! {
         static ref RL: Mutex<Editor<(), rustyline::history::DefaultHistory>>
@@ -76,18 +170,86 @@ fn readline(a: MalArgs) -> MalRet {
                 Err(e) => error(&format!("{:?}", e)),
             }
         }
-        _ => error("readline: prompt is not Str"),
+        _ => type_error("readline: prompt is not Str"),
     }
 }
 
-fn slurp(f: &str) -> MalRet {
+// Comments as {:text :line :column} maps, for tools such as formatters
+// that need what read-string drops.
+fn comments(s: &str) -> MalRet {
+    let mut res = vec![];
+    for (text, line, col) in read_comments(s) {
+        res.push(hash_map(vec![
+            Str("\u{29e}text".to_string()),
+            Str(text),
+            Str("\u{29e}line".to_string()),
+            Int(line as i64),
+            Str("\u{29e}column".to_string()),
+            Int(col as i64),
+        ])?);
+    }
+    Ok(list!(res))
+}
+
+fn read_file(f: &str) -> Result<String, MalErr> {
     let mut s = String::new();
     match File::open(f).and_then(|mut f| f.read_to_string(&mut s)) {
+        Ok(_) => Ok(s),
+        Err(e) => Err(ErrString(e.to_string())),
+    }
+}
+
+fn slurp(f: &str) -> MalRet {
+    Ok(Str(read_file(f)?))
+}
+
+fn slurp_lines(f: &str) -> MalRet {
+    Ok(list!(read_file(f)?.lines().map(|l| Str(l.to_string())).collect()))
+}
+
+// Everything left in input, which is stdin for the builtin and can be
+// any reader in tests.
+fn slurp_stdin<R: Read>(input: &mut R) -> MalRet {
+    let mut s = String::new();
+    match input.read_to_string(&mut s) {
         Ok(_) => Ok(Str(s)),
         Err(e) => error(&e.to_string()),
     }
 }
 
+// Fills each {} in the format string with the next argument, printed
+// as str would. {{ and }} stand for literal braces.
+fn format(a: MalArgs) -> MalRet {
+    let fmt = match a[0] {
+        Str(ref s) if !a[0].keyword_q() => s,
+        _ => return type_error("format: expected a format string"),
+    };
+    let mut args = a[1..].iter();
+    let mut res = String::new();
+    let mut chars = fmt.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, chars.peek()) {
+            ('{', Some('{')) | ('}', Some('}')) => {
+                chars.next();
+                res.push(c);
+            }
+            ('{', Some('}')) => {
+                chars.next();
+                match args.next() {
+                    Some(arg) => res.push_str(&arg.pr_str(false)),
+                    None => return error("format: more placeholders than arguments"),
+                }
+            }
+            ('{', _) | ('}', _) => return error("format: unmatched brace"),
+            _ => res.push(c),
+        }
+    }
+    if args.next().is_some() {
+        return error("format: more arguments than placeholders");
+    }
+    Ok(Str(res))
+}
+
 fn time_ms(_a: MalArgs) -> MalRet {
     let ms_e = match SystemTime::now().duration_since(UNIX_EPOCH) {
         Ok(d) => d,
@@ -98,56 +260,151 @@ fn time_ms(_a: MalArgs) -> MalRet {
     ))
 }
 
+fn time_ns(_a: MalArgs) -> MalRet {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(d) => Ok(Int(d.as_nanos() as i64)),
+        Err(e) => error(&format!("{:?}", e)),
+    }
+}
+
+// An optional third argument is returned when the key is absent.
 fn get(a: MalArgs) -> MalRet {
+    let default = a.get(2).cloned().unwrap_or(Nil);
     match (a[0].clone(), a[1].clone()) {
-        (Nil, _) => Ok(Nil),
-        (Hash(ref hm, _), Str(ref s)) => match hm.get(s) {
+        (Nil, _) => Ok(default),
+        (Hash(ref hm, _), k) => match hm.get(&k) {
             Some(mv) => Ok(mv.clone()),
-            None => Ok(Nil),
+            None => Ok(default),
         },
-        _ => error("illegal get args"),
+        (Set(ref s, _), k) => Ok(s.get(&k).cloned().unwrap_or(default)),
+        _ => type_error("illegal get args"),
     }
 }
 
 fn assoc(a: MalArgs) -> MalRet {
     match a[0] {
         Hash(ref hm, _) => _assoc((**hm).clone(), a[1..].to_vec()),
-        _ => error("assoc on non-Hash Map"),
+        Vector(ref v, _) => assoc_vec((**v).clone(), &a[1..]),
+        _ => type_error("assoc on non-Hash Map"),
+    }
+}
+
+// An index one past the end appends, as in Clojure.
+fn assoc_vec(mut v: Vec<MalVal>, kvs: &[MalVal]) -> MalRet {
+    if !kvs.len().is_multiple_of(2) {
+        return arity_error("odd number of elements");
+    }
+    for kv in kvs.chunks(2) {
+        match kv[0] {
+            Int(i) if i >= 0 && (i as usize) < v.len() => v[i as usize] = kv[1].clone(),
+            Int(i) if i >= 0 && (i as usize) == v.len() => v.push(kv[1].clone()),
+            Int(_) => return error("assoc: index out of range"),
+            _ => return type_error("assoc: vector index is not an integer"),
+        }
+    }
+    Ok(vector!(v))
+}
+
+fn disj(a: MalArgs) -> MalRet {
+    match a[0] {
+        Set(ref s, _) => {
+            let mut s = (**s).clone();
+            for k in a[1..].iter() {
+                let _ = s.shift_remove(k);
+            }
+            Ok(Set(Rc::new(s), Rc::new(Nil)))
+        }
+        Nil => Ok(Nil),
+        _ => type_error("disj on non-set"),
     }
 }
 
 fn dissoc(a: MalArgs) -> MalRet {
     match a[0] {
         Hash(ref hm, _) => _dissoc((**hm).clone(), a[1..].to_vec()),
-        _ => error("dissoc on non-Hash Map"),
+        _ => type_error("dissoc on non-Hash Map"),
+    }
+}
+
+fn merge(a: MalArgs) -> MalRet {
+    if a.iter().all(|m| matches!(m, Nil)) {
+        return Ok(Nil);
+    }
+    let mut new_hm = MalMap::default();
+    for m in a.iter() {
+        match m {
+            Hash(hm, _) => new_hm.extend(hm.iter().map(|(k, v)| (k.clone(), v.clone()))),
+            Nil => (),
+            _ => return type_error("merge on non-Hash Map"),
+        }
     }
+    Ok(Hash(Rc::new(new_hm), Rc::new(Nil)))
 }
 
+fn select_keys(a: MalArgs) -> MalRet {
+    let ks = match a[1] {
+        List(ref v, _) | Vector(ref v, _) => v,
+        _ => return type_error("select-keys: keys must be a sequence"),
+    };
+    let mut new_hm = MalMap::default();
+    match a[0] {
+        Hash(ref hm, _) => {
+            for k in ks.iter() {
+                if let Some(v) = hm.get(k) {
+                    new_hm.insert(k.clone(), v.clone());
+                }
+            }
+        }
+        Nil => (),
+        _ => return type_error("select-keys on non-Hash Map"),
+    }
+    Ok(Hash(Rc::new(new_hm), Rc::new(Nil)))
+}
+
+// Key presence in a map or membership in a set; index-of and in? test
+// membership in sequences.
 fn contains_q(a: MalArgs) -> MalRet {
     match (a[0].clone(), a[1].clone()) {
-        (Hash(ref hm, _), Str(ref s)) => Ok(Bool(hm.contains_key(s))),
-        _ => error("illegal get args"),
+        (Hash(ref hm, _), ref k) => Ok(Bool(hm.contains_key(k))),
+        (Set(ref s, _), ref k) => Ok(Bool(s.contains(k))),
+        (Nil, _) => Ok(Bool(false)),
+        _ => type_error("illegal get args"),
     }
 }
 
+// Position of the first element equal to x, or of the first occurrence
+// of a substring, counted in characters.
+fn index_of(a: MalArgs) -> MalRet {
+    let i = match (&a[0], &a[1]) {
+        (List(v, _), x) | (Vector(v, _), x) => v.iter().position(|mv| mv == x),
+        (Nil, _) => None,
+        (Str(s), Str(sub)) if !a[0].keyword_q() && !a[1].keyword_q() => {
+            s.find(sub.as_str()).map(|b| s[..b].chars().count())
+        }
+        _ => return type_error("index-of: expected a sequence or a string and a substring"),
+    };
+    Ok(i.map_or(Nil, |i| Int(i as i64)))
+}
+
 fn keys(a: MalArgs) -> MalRet {
     match a[0] {
-        Hash(ref hm, _) => Ok(list!(hm.keys().map(|k| { Str(k.to_string()) }).collect())),
-        _ => error("keys requires Hash Map"),
+        Hash(ref hm, _) => Ok(list!(hm.keys().cloned().collect())),
+        _ => type_error("keys requires Hash Map"),
     }
 }
 
 fn vals(a: MalArgs) -> MalRet {
     match a[0] {
         Hash(ref hm, _) => Ok(list!(hm.values().cloned().collect())),
-        _ => error("keys requires Hash Map"),
+        _ => type_error("keys requires Hash Map"),
     }
 }
 
 fn vec(a: MalArgs) -> MalRet {
     match a[0] {
         List(ref v, _) | Vector(ref v, _) => Ok(vector!(v.to_vec())),
-        _ => error("non-seq passed to vec"),
+        Nil => Ok(vector![]),
+        _ => type_error("non-seq passed to vec"),
     }
 }
 
@@ -158,7 +415,8 @@ fn cons(a: MalArgs) -> MalRet {
             new_v.extend_from_slice(&v);
             Ok(list!(new_v.to_vec()))
         }
-        _ => error("cons expects seq as second arg"),
+        Nil => Ok(list!(vec![a[0].clone()])),
+        _ => type_error("cons expects seq as second arg"),
     }
 }
 
@@ -167,7 +425,8 @@ fn concat(a: MalArgs) -> MalRet {
     for seq in a.iter() {
         match seq {
             List(v, _) | Vector(v, _) => new_v.extend_from_slice(v),
-            _ => return error("non-seq passed to concat"),
+            Nil => (),
+            _ => return type_error("non-seq passed to concat"),
         }
     }
     Ok(list!(new_v.to_vec()))
@@ -181,16 +440,16 @@ fn nth(a: MalArgs) -> MalRet {
             }
             Ok(seq[idx as usize].clone())
         }
-        _ => error("invalid args to nth"),
+        _ => type_error("invalid args to nth"),
     }
 }
 
 fn first(a: MalArgs) -> MalRet {
     match a[0].clone() {
-        List(ref seq, _) | Vector(ref seq, _) if seq.len() == 0 => Ok(Nil),
+        List(ref seq, _) | Vector(ref seq, _) if seq.is_empty() => Ok(Nil),
         List(ref seq, _) | Vector(ref seq, _) => Ok(seq[0].clone()),
         Nil => Ok(Nil),
-        _ => error("invalid args to first"),
+        _ => type_error("invalid args to first"),
     }
 }
 
@@ -204,7 +463,81 @@ fn rest(a: MalArgs) -> MalRet {
             }
         }
         Nil => Ok(list![]),
-        _ => error("invalid args to first"),
+        _ => type_error("invalid args to first"),
+    }
+}
+
+// take and drop clamp n to the length of the sequence.
+fn take(a: MalArgs) -> MalRet {
+    match (&a[0], &a[1]) {
+        (Int(n), List(v, _)) | (Int(n), Vector(v, _)) => {
+            Ok(list!(v[..(*n).clamp(0, v.len() as i64) as usize].to_vec()))
+        }
+        (Int(_), Nil) => Ok(list![]),
+        _ => type_error("take: expected a count and a sequence"),
+    }
+}
+
+fn drop(a: MalArgs) -> MalRet {
+    match (&a[0], &a[1]) {
+        (Int(n), List(v, _)) | (Int(n), Vector(v, _)) => {
+            Ok(list!(v[(*n).clamp(0, v.len() as i64) as usize..].to_vec()))
+        }
+        (Int(_), Nil) => Ok(list![]),
+        _ => type_error("drop: expected a count and a sequence"),
+    }
+}
+
+fn subvec(a: MalArgs) -> MalRet {
+    let v = match a[0] {
+        Vector(ref v, _) => v,
+        _ => return type_error("subvec: expected a vector"),
+    };
+    let (start, end) = match (&a[1], a.get(2)) {
+        (Int(s), None) => (*s, v.len() as i64),
+        (Int(s), Some(Int(e))) => (*s, *e),
+        _ => return type_error("subvec: indices must be integers"),
+    };
+    if start < 0 || end < start || end > v.len() as i64 {
+        return error("subvec: index out of range");
+    }
+    Ok(vector!(v[start as usize..end as usize].to_vec()))
+}
+
+fn divide(a: MalArgs) -> MalRet {
+    match (&a[0], &a[1]) {
+        (Int(_), Int(0)) => error("division by zero"),
+        _ => num_op("/", &a, i64::checked_div, |x, y| x / y),
+    }
+}
+
+// An integer to a non-negative integer power stays an exact integer;
+// a negative or float exponent, or a float base, gives a float.
+fn pow(a: MalArgs) -> MalRet {
+    match (&a[0], &a[1]) {
+        (Int(b), Int(e)) if *e >= 0 => {
+            match u32::try_from(*e).ok().and_then(|e| b.checked_pow(e)) {
+                Some(r) => Ok(Int(r)),
+                None => error("pow: integer overflow"),
+            }
+        }
+        _ => match (as_float(&a[0]), as_float(&a[1])) {
+            (Some(b), Some(e)) => Ok(Float(b.powf(e))),
+            _ => num_args_error(&a),
+        },
+    }
+}
+
+// Macros take their arguments unevaluated, so the sequence functions
+// refuse them. apply still calls the underlying function: the required
+// "Testing apply function with macros" cases in tests/step9_try.mal
+// expect (apply m (list 2 3)) to return what m's function returns.
+fn as_fn(f: &MalVal) -> Result<&MalVal, MalErr> {
+    match f {
+        MalFunc { is_macro: true, .. } => {
+            Err(TypeError("cannot apply a macro as a function".to_string()))
+        }
+        _ => Ok(f),
     }
 }
 
@@ -216,23 +549,255 @@ fn apply(a: MalArgs) -> MalRet {
             fargs.extend_from_slice(v);
             f.apply(fargs)
         }
-        _ => error("apply called with non-seq"),
+        Nil => a[0].apply(a[1..a.len() - 1].to_vec()),
+        _ => type_error("apply: last argument must be a sequence"),
     }
 }
 
 fn map(a: MalArgs) -> MalRet {
+    let f = as_fn(&a[0])?;
     match a[1] {
         List(ref v, _) | Vector(ref v, _) => {
             let mut res = vec![];
             for mv in v.iter() {
-                res.push(a[0].apply(vec![mv.clone()])?)
+                res.push(f.apply(vec![mv.clone()])?)
             }
             Ok(list!(res))
         }
-        _ => error("map called with non-seq"),
+        Nil => Ok(list![]),
+        _ => type_error("map called with non-seq"),
+    }
+}
+
+fn filter(a: MalArgs) -> MalRet {
+    let f = as_fn(&a[0])?;
+    match a[1] {
+        List(ref v, _) | Vector(ref v, _) => {
+            let mut res = vec![];
+            for mv in v.iter() {
+                if f.apply(vec![mv.clone()])?.truthy() {
+                    res.push(mv.clone());
+                }
+            }
+            Ok(list!(res))
+        }
+        Nil => Ok(list![]),
+        _ => type_error("filter called with non-seq"),
     }
 }
 
+fn reduce(a: MalArgs) -> MalRet {
+    let f = as_fn(&a[0])?;
+    let (init, coll) = match a.len() {
+        2 => (None, &a[1]),
+        3 => (Some(a[1].clone()), &a[2]),
+        _ => return arity_error("reduce: expected 2 or 3 arguments"),
+    };
+    let v = match coll {
+        List(v, _) | Vector(v, _) => &v[..],
+        Nil => &[],
+        _ => return type_error("reduce called with non-seq"),
+    };
+    let (mut acc, rest) = match init {
+        Some(init) => (init, v),
+        None => match v.split_first() {
+            Some((first, rest)) => (first.clone(), rest),
+            None => return f.apply(vec![]),
+        },
+    };
+    for mv in rest.iter() {
+        acc = f.apply(vec![acc, mv.clone()])?;
+    }
+    Ok(acc)
+}
+
+// A stable merge sort, as cmp may fail or not be a total order.
+fn merge_sort(
+    mut v: Vec<MalVal>,
+    cmp: &dyn Fn(&MalVal, &MalVal) -> Result<Ordering, MalErr>,
+) -> Result<Vec<MalVal>, MalErr> {
+    if v.len() < 2 {
+        return Ok(v);
+    }
+    let right = merge_sort(v.split_off(v.len() / 2), cmp)?;
+    let left = merge_sort(v, cmp)?;
+    let mut res = Vec::with_capacity(left.len() + right.len());
+    let (mut left, mut right) = (left.into_iter().peekable(), right.into_iter().peekable());
+    while let (Some(l), Some(r)) = (left.peek(), right.peek()) {
+        if cmp(r, l)? == Ordering::Less {
+            res.extend(right.next());
+        } else {
+            res.extend(left.next());
+        }
+    }
+    res.extend(left);
+    res.extend(right);
+    Ok(res)
+}
+
+// With a comparator, which may return a boolean "a comes before b" or
+// a number whose sign orders a and b. Otherwise numbers and strings
+// sort in their natural order.
+fn sort(a: MalArgs) -> MalRet {
+    let (f, coll) = match a.len() {
+        1 => (None, &a[0]),
+        2 => (Some(as_fn(&a[0])?), &a[1]),
+        _ => return arity_error("sort: expected 1 or 2 arguments"),
+    };
+    let v = match coll {
+        List(v, _) | Vector(v, _) => v.to_vec(),
+        Nil => vec![],
+        _ => return type_error("sort called with non-seq"),
+    };
+    let res = match f {
+        Some(f) => merge_sort(v, &|x, y| match f.apply(vec![x.clone(), y.clone()])? {
+            Int(i) => Ok(i.cmp(&0)),
+            Float(f) => Ok(f.partial_cmp(&0.0).unwrap_or(Ordering::Equal)),
+            r if r.truthy() => Ok(Ordering::Less),
+            _ if f.apply(vec![y.clone(), x.clone()])?.truthy() => Ok(Ordering::Greater),
+            _ => Ok(Ordering::Equal),
+        })?,
+        None => merge_sort(v, &|x, y| match (x, y) {
+            (Int(i), Int(j)) => Ok(i.cmp(j)),
+            (Str(s), Str(t)) => Ok(s.cmp(t)),
+            _ => match (as_float(x), as_float(y)) {
+                (Some(f), Some(g)) => Ok(f.partial_cmp(&g).unwrap_or(Ordering::Equal)),
+                _ => Err(TypeError(format!(
+                    "sort: cannot compare {} and {}",
+                    x.pr_str(true),
+                    y.pr_str(true)
+                ))),
+            },
+        })?,
+    };
+    Ok(list!(res))
+}
+
+fn fn_params(a: MalArgs) -> MalRet {
+    match a[0] {
+        MalFunc {
+            ref params,
+            ref ast,
+            ..
+        } => match (&**params, &**ast) {
+            (List(ref v, _), _) | (Vector(ref v, _), _) => Ok(list!(v.to_vec())),
+            // One parameter list per clause of a multi-arity function.
+            (Nil, List(ref clauses, _)) => Ok(list!(clauses
+                .iter()
+                .map(|c| match c {
+                    List(c, _) => match c[0] {
+                        List(ref v, _) | Vector(ref v, _) => list!(v.to_vec()),
+                        _ => Nil,
+                    },
+                    _ => Nil,
+                })
+                .collect())),
+            _ => type_error("fn-params: invalid parameter list"),
+        },
+        Func(_, _) => Ok(Nil),
+        _ => type_error("fn-params: expected a function"),
+    }
+}
+
+fn flatten(a: MalArgs) -> MalRet {
+    fn flatten_into(v: &[MalVal], res: &mut Vec<MalVal>) {
+        for mv in v.iter() {
+            match mv {
+                List(v, _) | Vector(v, _) => flatten_into(v, res),
+                _ => res.push(mv.clone()),
+            }
+        }
+    }
+    match a[0] {
+        List(ref v, _) | Vector(ref v, _) => {
+            let mut res = vec![];
+            flatten_into(v, &mut res);
+            Ok(list!(res))
+        }
+        Nil => Ok(list![]),
+        _ => type_error("flatten called with non-seq"),
+    }
+}
+
+fn interpose(a: MalArgs) -> MalRet {
+    match a[1] {
+        List(ref v, _) | Vector(ref v, _) => {
+            let mut res = vec![];
+            for (i, mv) in v.iter().enumerate() {
+                if i > 0 {
+                    res.push(a[0].clone());
+                }
+                res.push(mv.clone());
+            }
+            Ok(list!(res))
+        }
+        Nil => Ok(list![]),
+        _ => type_error("interpose called with non-seq"),
+    }
+}
+
+fn str_join(a: MalArgs) -> MalRet {
+    match (&a[0], &a[1]) {
+        (Str(sep), List(v, _)) | (Str(sep), Vector(v, _)) => Ok(Str(v
+            .iter()
+            .map(|mv| mv.pr_str(false))
+            .collect::<Vec<String>>()
+            .join(sep))),
+        (Str(_), Nil) => Ok(Str(String::new())),
+        _ => type_error("str-join: expected a separator string and a sequence"),
+    }
+}
+
+// The string argument at i, refusing keywords.
+fn str_arg<'a>(a: &'a MalArgs, i: usize, name: &str) -> Result<&'a str, MalErr> {
+    match a.get(i) {
+        Some(v @ Str(s)) if !v.keyword_q() => Ok(s),
+        _ => Err(TypeError(format!("{}: expected a string as argument {}", name, i + 1))),
+    }
+}
+
+// Indexes count characters, not bytes; the end defaults to the length.
+fn subs(a: MalArgs) -> MalRet {
+    let s = str_arg(&a, 0, "subs")?;
+    let len = s.chars().count() as i64;
+    let (start, end) = match (a.get(1), a.get(2)) {
+        (Some(Int(i)), None) => (*i, len),
+        (Some(Int(i)), Some(Int(j))) => (*i, *j),
+        _ => return type_error("subs: expected integer indexes"),
+    };
+    if start < 0 || end < start || end > len {
+        return error("subs: index out of range");
+    }
+    Ok(Str(s.chars().skip(start as usize).take((end - start) as usize).collect()))
+}
+
+fn str_split(a: MalArgs) -> MalRet {
+    let s = str_arg(&a, 0, "str/split")?;
+    let sep = str_arg(&a, 1, "str/split")?;
+    let parts: MalArgs = if sep.is_empty() {
+        s.chars().map(|c| Str(c.to_string())).collect()
+    } else {
+        s.split(sep).map(|p| Str(p.to_string())).collect()
+    };
+    Ok(list!(parts))
+}
+
+fn str_replace(a: MalArgs) -> MalRet {
+    let s = str_arg(&a, 0, "str/replace")?;
+    let from = str_arg(&a, 1, "str/replace")?;
+    let to = str_arg(&a, 2, "str/replace")?;
+    if from.is_empty() {
+        return error("str/replace: empty string to replace");
+    }
+    Ok(Str(s.replace(from, to)))
+}
+
+fn str_index_of(a: MalArgs) -> MalRet {
+    str_arg(&a, 0, "str/index-of")?;
+    str_arg(&a, 1, "str/index-of")?;
+    index_of(a)
+}
+
 fn conj(a: MalArgs) -> MalRet {
     match a[0] {
         List(ref v, _) => {
@@ -244,32 +809,176 @@ fn conj(a: MalArgs) -> MalRet {
             Ok(list!([&sl[..], v].concat()))
         }
         Vector(ref v, _) => Ok(vector!([v, &a[1..]].concat())),
-        _ => error("conj: called with non-seq"),
+        Set(ref s, _) => _conj_set((**s).clone(), &a[1..]),
+        Nil => Ok(list!(a[1..].iter().rev().cloned().collect())),
+        _ => type_error("conj: called with non-seq"),
     }
 }
 
 fn seq(a: MalArgs) -> MalRet {
     match a[0] {
-        List(ref v, _) | Vector(ref v, _) if v.len() == 0 => Ok(Nil),
+        List(ref v, _) | Vector(ref v, _) if v.is_empty() => Ok(Nil),
         List(ref v, _) | Vector(ref v, _) => Ok(list!(v.to_vec())),
         Str(ref s) if s.is_empty() => Ok(Nil),
         Str(ref s) if !a[0].keyword_q() => {
             Ok(list!(s.chars().map(|c| { Str(c.to_string()) }).collect()))
         }
+        Hash(ref hm, _) if hm.is_empty() => Ok(Nil),
+        Hash(ref hm, _) => Ok(list!(hm
+            .iter()
+            .map(|(k, v)| vector![k.clone(), v.clone()])
+            .collect())),
+        Set(ref s, _) if s.is_empty() => Ok(Nil),
+        Set(ref s, _) => Ok(list!(s.iter().cloned().collect())),
         Nil => Ok(Nil),
-        _ => error("seq: called with non-seq"),
+        _ => type_error("seq: called with non-seq"),
+    }
+}
+
+// Adds the items of from to to with conj, so a list target comes out
+// reversed. A map target takes [k v] pairs.
+fn into(a: MalArgs) -> MalRet {
+    let items = match seq(vec![a[1].clone()])? {
+        List(v, _) => v.to_vec(),
+        _ => vec![],
+    };
+    match a[0] {
+        Hash(ref hm, _) => {
+            let mut kvs = vec![];
+            for item in items {
+                match item {
+                    List(ref kv, _) | Vector(ref kv, _) if kv.len() == 2 => {
+                        kvs.extend(kv.iter().cloned());
+                    }
+                    _ => return type_error("into: map entries must be [key value] pairs"),
+                }
+            }
+            _assoc((**hm).clone(), kvs)
+        }
+        _ => conj([vec![a[0].clone()], items].concat()),
+    }
+}
+
+// The elements of coll, which may be anything seq takes.
+fn set(a: MalArgs) -> MalRet {
+    match seq(a)? {
+        List(v, _) => hash_set(v.to_vec()),
+        _ => hash_set(vec![]),
+    }
+}
+
+fn re_pattern(a: MalArgs) -> MalRet {
+    match a[0] {
+        Pattern(_) => Ok(a[0].clone()),
+        Str(ref s) if !a[0].keyword_q() => pattern(s),
+        _ => type_error("re-pattern: expected a string"),
+    }
+}
+
+fn pattern_args<'a>(a: &'a MalArgs, name: &str) -> Result<(&'a regex::Regex, &'a str), MalErr> {
+    match (&a[0], &a[1]) {
+        (Pattern(re), s @ Str(ref st)) if !s.keyword_q() => Ok((re, st)),
+        _ => Err(TypeError(format!("{}: expected a pattern and a string", name))),
+    }
+}
+
+// The text a match covers or, when the pattern has groups, a vector of
+// it and each group, with nil for a group that took no part.
+fn match_val(caps: &regex::Captures) -> MalVal {
+    let text = |m: Option<regex::Match>| m.map_or(Nil, |m| Str(m.as_str().to_string()));
+    if caps.len() == 1 {
+        return text(caps.get(0));
+    }
+    vector!(caps.iter().map(text).collect())
+}
+
+fn re_find(a: MalArgs) -> MalRet {
+    let (re, s) = pattern_args(&a, "re-find")?;
+    Ok(re.captures(s).map_or(Nil, |c| match_val(&c)))
+}
+
+// Like re-find, but the match must cover the whole string.
+fn re_matches(a: MalArgs) -> MalRet {
+    let (re, s) = pattern_args(&a, "re-matches")?;
+    let whole = regex::Regex::new(&format!("^(?:{})$", re.as_str()))
+        .map_err(|e| ErrString(format!("re-matches: {}", e)))?;
+    Ok(whole.captures(s).map_or(Nil, |c| match_val(&c)))
+}
+
+fn re_seq(a: MalArgs) -> MalRet {
+    let (re, s) = pattern_args(&a, "re-seq")?;
+    let matches: MalArgs = re.captures_iter(s).map(|c| match_val(&c)).collect();
+    Ok(if matches.is_empty() { Nil } else { list!(matches) })
+}
+
+// The argument counts each builtin accepts, as (min, max). Builtins
+// not listed take any number.
+fn arity(name: &str) -> (usize, Option<usize>) {
+    match name {
+        "slurp-stdin" | "time-ms" | "time-ns" => (0, Some(0)),
+        "gensym" => (0, Some(1)),
+        "throw" | "nil?" | "true?" | "false?" | "truthy?" | "boolean" | "symbol" | "symbol?"
+        | "name" | "namespace" | "string?" | "keyword" | "keyword?" | "number?" | "fn?"
+        | "macro?" | "fn-params" | "str/upper-case" | "str/lower-case" | "str/trim"
+        | "pprint" | "read-string" | "read-tokens" | "read-comments" | "readline" | "slurp"
+        | "slurp-lines" | "sequential?" | "list?" | "vector?" | "map?" | "keys" | "vals"
+        | "vec" | "flatten" | "empty?" | "first" | "rest" | "count" | "seq" | "meta"
+        | "atom" | "atom?" | "deref" | "set" | "set?" | "re-pattern" | "pattern?" => (1, Some(1)),
+        "sort" => (1, Some(2)),
+        "=" | "identical?" | "str-join" | "str/split" | "str/join" | "str/starts-with?"
+        | "str/index-of" | "<" | "<=" | ">" | ">=" | "+" | "-" | "*" | "/" | "pow"
+        | "contains?" | "index-of" | "in?" | "select-keys" | "cons" | "interpose" | "nth"
+        | "take" | "drop" | "map" | "filter" | "into" | "with-meta" | "reset!" | "re-find"
+        | "re-matches" | "re-seq" => (2, Some(2)),
+        "subs" | "get" | "subvec" | "reduce" => (2, Some(3)),
+        "str/replace" | "compare-and-set!" => (3, Some(3)),
+        "format" | "assoc" | "dissoc" | "disj" | "conj" => (1, None),
+        "apply" | "swap!" => (2, None),
+        _ => (0, None),
+    }
+}
+
+// Checking argument counts here means no builtin has to before it
+// indexes its arguments.
+fn with_arity(name: &'static str, f: MalVal) -> MalVal {
+    match (arity(name), f) {
+        ((min, max), Func(f, meta)) if (min, max) != (0, None) => Func(
+            Rc::new(move |a: MalArgs| {
+                check_arity(name, a.len(), min, max)?;
+                f(a)
+            }),
+            meta,
+        ),
+        (_, f) => f,
     }
 }
 
 pub fn ns() -> Vec<(&'static str, MalVal)> {
+    builtins()
+        .into_iter()
+        .map(|(name, f)| (name, with_arity(name, f)))
+        .collect()
+}
+
+fn builtins() -> Vec<(&'static str, MalVal)> {
     vec![
         ("=", func(|a| Ok(Bool(a[0] == a[1])))),
-        ("throw", func(|a| Err(ErrMalVal(a[0].clone())))),
+        ("identical?", func(|a| Ok(Bool(a[0].identical(&a[1]))))),
+        ("throw", func(|a| Err(Thrown(a[0].clone())))),
         ("nil?", func(fn_is_type!(Nil))),
         ("true?", func(fn_is_type!(Bool(true)))),
         ("false?", func(fn_is_type!(Bool(false)))),
+        ("truthy?", func(|a| Ok(Bool(a[0].truthy())))),
+        ("boolean", func(|a| Ok(Bool(a[0].truthy())))),
         ("symbol", func(symbol)),
         ("symbol?", func(fn_is_type!(Sym(_)))),
+        ("gensym", func(|a| match a.first() {
+            None => Ok(gensym("G__")),
+            Some(p @ Str(s)) if !p.keyword_q() => Ok(gensym(s)),
+            _ => type_error("gensym: expected a prefix string"),
+        })),
+        ("name", func(name)),
+        ("namespace", func(namespace)),
         (
             "string?",
             func(fn_is_type!(Str(ref s) if !s.starts_with('\u{29e}'))),
@@ -279,7 +988,7 @@ pub fn ns() -> Vec<(&'static str, MalVal)> {
             "keyword?",
             func(fn_is_type!(Str(ref s) if s.starts_with('\u{29e}'))),
         ),
-        ("number?", func(fn_is_type!(Int(_)))),
+        ("number?", func(fn_is_type!(Int(_), Float(_)))),
         (
             "fn?",
             func(fn_is_type!(MalFunc{is_macro,..} if !is_macro,Func(_,_))),
@@ -288,8 +997,29 @@ pub fn ns() -> Vec<(&'static str, MalVal)> {
             "macro?",
             func(fn_is_type!(MalFunc{is_macro,..} if is_macro)),
         ),
+        ("fn-params", func(fn_params)),
         ("pr-str", func(|a| Ok(Str(pr_seq(&a, true, "", "", " "))))),
         ("str", func(|a| Ok(Str(pr_seq(&a, false, "", "", ""))))),
+        ("format", func(format)),
+        ("print-str", func(|a| Ok(Str(pr_seq(&a, false, "", "", " "))))),
+        ("str-join", func(str_join)),
+        ("subs", func(subs)),
+        ("str/upper-case", func(|a| Ok(Str(str_arg(&a, 0, "str/upper-case")?.to_uppercase())))),
+        ("str/lower-case", func(|a| Ok(Str(str_arg(&a, 0, "str/lower-case")?.to_lowercase())))),
+        ("str/trim", func(|a| Ok(Str(str_arg(&a, 0, "str/trim")?.trim().to_string())))),
+        ("str/split", func(str_split)),
+        ("str/join", func(str_join)),
+        ("str/replace", func(str_replace)),
+        ("str/starts-with?", func(|a| {
+            let s = str_arg(&a, 0, "str/starts-with?")?;
+            Ok(Bool(s.starts_with(str_arg(&a, 1, "str/starts-with?")?)))
+        })),
+        ("str/index-of", func(str_index_of)),
+        ("re-pattern", func(re_pattern)),
+        ("pattern?", func(fn_is_type!(Pattern(_)))),
+        ("re-find", func(re_find)),
+        ("re-matches", func(re_matches)),
+        ("re-seq", func(re_seq)),
         (
             "prn",
             func(|a| {
@@ -297,6 +1027,13 @@ pub fn ns() -> Vec<(&'static str, MalVal)> {
                 Ok(Nil)
             }),
         ),
+        (
+            "pprint",
+            func(|a| {
+                println!("{}", a[0].pr_pretty(true, 0));
+                Ok(Nil)
+            }),
+        ),
         (
             "println",
             func(|a| {
@@ -305,17 +1042,26 @@ pub fn ns() -> Vec<(&'static str, MalVal)> {
             }),
         ),
         ("read-string", func(fn_str!(read_str))),
+        (
+            "read-tokens",
+            func(fn_str!(|s| Ok(list!(tokenize_source(s).into_iter().map(Str).collect())))),
+        ),
+        ("read-comments", func(fn_str!(comments))),
         ("readline", func(readline)),
         ("slurp", func(fn_str!(slurp))),
-        ("<", func(fn_t_int_int!(Bool, |i, j| { i < j }))),
-        ("<=", func(fn_t_int_int!(Bool, |i, j| { i <= j }))),
-        (">", func(fn_t_int_int!(Bool, |i, j| { i > j }))),
-        (">=", func(fn_t_int_int!(Bool, |i, j| { i >= j }))),
-        ("+", func(fn_t_int_int!(Int, |i, j| { i + j }))),
-        ("-", func(fn_t_int_int!(Int, |i, j| { i - j }))),
-        ("*", func(fn_t_int_int!(Int, |i, j| { i * j }))),
-        ("/", func(fn_t_int_int!(Int, |i, j| { i / j }))),
+        ("slurp-lines", func(fn_str!(slurp_lines))),
+        ("slurp-stdin", func(|_| slurp_stdin(&mut std::io::stdin()))),
+        ("<", func(|a| compare(&a, Ordering::is_lt))),
+        ("<=", func(|a| compare(&a, Ordering::is_le))),
+        (">", func(|a| compare(&a, Ordering::is_gt))),
+        (">=", func(|a| compare(&a, Ordering::is_ge))),
+        ("+", func(|a| num_op("+", &a, i64::checked_add, |x, y| x + y))),
+        ("-", func(|a| num_op("-", &a, i64::checked_sub, |x, y| x - y))),
+        ("*", func(|a| num_op("*", &a, i64::checked_mul, |x, y| x * y))),
+        ("/", func(divide)),
+        ("pow", func(pow)),
         ("time-ms", func(time_ms)),
+        ("time-ns", func(time_ns)),
         ("sequential?", func(fn_is_type!(List(_, _), Vector(_, _)))),
         ("list", func(|a| Ok(list!(a.to_vec())))),
         ("list?", func(fn_is_type!(List(_, _)))),
@@ -323,24 +1069,41 @@ pub fn ns() -> Vec<(&'static str, MalVal)> {
         ("vector?", func(fn_is_type!(Vector(_, _)))),
         ("hash-map", func(hash_map)),
         ("map?", func(fn_is_type!(Hash(_, _)))),
+        ("hash-set", func(hash_set)),
+        ("set", func(set)),
+        ("set?", func(fn_is_type!(Set(_, _)))),
+        ("disj", func(disj)),
         ("assoc", func(assoc)),
         ("dissoc", func(dissoc)),
         ("get", func(get)),
         ("contains?", func(contains_q)),
+        ("index-of", func(index_of)),
+        ("in?", func(|a| Ok(Bool(index_of(a)? != Nil)))),
+        ("merge", func(merge)),
+        ("select-keys", func(select_keys)),
         ("keys", func(keys)),
         ("vals", func(vals)),
         ("vec", func(vec)),
         ("cons", func(cons)),
         ("concat", func(concat)),
+        ("flatten", func(flatten)),
+        ("interpose", func(interpose)),
         ("empty?", func(|a| a[0].empty_q())),
         ("nth", func(nth)),
         ("first", func(first)),
         ("rest", func(rest)),
+        ("take", func(take)),
+        ("drop", func(drop)),
+        ("subvec", func(subvec)),
         ("count", func(|a| a[0].count())),
         ("apply", func(apply)),
         ("map", func(map)),
+        ("filter", func(filter)),
+        ("reduce", func(reduce)),
+        ("sort", func(sort)),
         ("conj", func(conj)),
         ("seq", func(seq)),
+        ("into", func(into)),
         ("meta", func(|a| a[0].get_meta())),
         ("with-meta", func(|a| a[0].clone().with_meta(&a[1]))),
         ("atom", func(|a| Ok(atom(&a[0])))),
@@ -348,5 +1111,50 @@ pub fn ns() -> Vec<(&'static str, MalVal)> {
         ("deref", func(|a| a[0].deref())),
         ("reset!", func(|a| a[0].reset_bang(&a[1]))),
         ("swap!", func(|a| a[0].swap_bang(&a[1..].to_vec()))),
+        (
+            "compare-and-set!",
+            func(|a| a[0].compare_and_set_bang(&a[1], &a[2])),
+        ),
     ]
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{ns, slurp_stdin};
+    use crate::types::MalVal::Int;
+    use crate::types::MalVal;
+
+    fn call(name: &str, args: Vec<MalVal>) -> String {
+        let (_, f) = ns().into_iter().find(|(n, _)| *n == name).unwrap();
+        match f.apply(args) {
+            Ok(v) => v.pr_str(true),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn arithmetic_overflow_is_an_error() {
+        assert_eq!(call("+", vec![Int(i64::MAX), Int(1)]), "+: integer overflow");
+        assert_eq!(call("-", vec![Int(i64::MIN), Int(1)]), "-: integer overflow");
+        assert_eq!(call("*", vec![Int(i64::MAX), Int(2)]), "*: integer overflow");
+        assert_eq!(call("/", vec![Int(i64::MIN), Int(-1)]), "/: integer overflow");
+        assert_eq!(call("+", vec![Int(i64::MAX - 1), Int(1)]), i64::MAX.to_string());
+    }
+
+    #[test]
+    fn slurp_stdin_reads_to_eof() {
+        let mut input = &b"one\ntwo\n\nthree\n"[..];
+        let s = slurp_stdin(&mut input).unwrap();
+        assert_eq!(s.pr_str(false), "one\ntwo\n\nthree\n");
+        assert_eq!(slurp_stdin(&mut input).unwrap().pr_str(true), "\"\"");
+    }
+
+    #[test]
+    fn slurp_stdin_rejects_invalid_utf8() {
+        let mut input = &b"caf\xe9"[..];
+        match slurp_stdin(&mut input) {
+            Err(e) => assert!(e.to_string().contains("valid UTF-8"), "{}", e),
+            Ok(s) => panic!("read {}", s.pr_str(true)),
+        }
+    }
+}
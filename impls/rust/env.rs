@@ -3,9 +3,9 @@ use std::rc::Rc;
 //use std::collections::HashMap;
 use fnv::FnvHashMap;
 
-use crate::types::MalErr::ErrString;
+use crate::types::MalErr::{ArityError, ErrString, UnknownSymbol};
 use crate::types::MalVal::{List, Nil, Sym, Vector};
-use crate::types::{error, MalErr, MalRet, MalVal};
+use crate::types::{func, type_error, MalArgs, MalErr, MalRet, MalVal};
 
 pub struct EnvStruct {
     data: RefCell<FnvHashMap<String, MalVal>>,
@@ -29,6 +29,16 @@ pub fn env_bind(outer: Option<Env>, mbinds: &MalVal, exprs: Vec<MalVal>) -> Resu
     let env = env_new(outer);
     match mbinds {
         List(binds, _) | Vector(binds, _) => {
+            let variadic = binds.iter().any(|b| matches!(b, Sym(s) if s == "&"));
+            let fixed = if variadic { binds.len().saturating_sub(2) } else { binds.len() };
+            if exprs.len() < fixed || (!variadic && exprs.len() > fixed) {
+                return Err(ArityError(format!(
+                    "wrong number of arguments: expected {}{}, got {}",
+                    if variadic { "at least " } else { "" },
+                    fixed,
+                    exprs.len()
+                )));
+            }
             for (i, b) in binds.iter().enumerate() {
                 match b {
                     Sym(s) if s == "&" => {
@@ -49,14 +59,74 @@ pub fn env_bind(outer: Option<Env>, mbinds: &MalVal, exprs: Vec<MalVal>) -> Resu
 pub fn env_get(env: &Env, key: &str) -> Option<MalVal> {
     let mut mut_env = env;
     loop {
-        if let Some(value) = mut_env.data.borrow().get(key) {
-            return Some(value.clone());
-        } else if let Some(outer) = &mut_env.outer {
-            mut_env = outer;
-        } else {
-            return None;
+        // Release each scope's borrow before moving on to the next.
+        let value = mut_env.data.borrow().get(key).cloned();
+        match (value, &mut_env.outer) {
+            (Some(value), _) => return Some(value),
+            (None, Some(outer)) => mut_env = outer,
+            (None, None) => return None,
+        }
+    }
+}
+
+pub fn env_keys(env: &Env) -> Vec<String> {
+    let mut keys = vec![];
+    let mut mut_env = env;
+    loop {
+        keys.extend(mut_env.data.borrow().keys().cloned());
+        match &mut_env.outer {
+            Some(outer) => mut_env = outer,
+            None => return keys,
+        }
+    }
+}
+
+pub fn env_not_found(env: &Env, key: &str) -> MalRet {
+    match env_suggest(env, key) {
+        Some(k) => Err(UnknownSymbol(format!("'{}' not found; did you mean '{}'?", key, k))),
+        None => Err(UnknownSymbol(format!("'{}' not found", key))),
+    }
+}
+
+// The closest bound name within a third of the key's length in edits.
+// Dropped characters are the most common typo, so among equally close
+// names prefer one that the key is a subsequence of.
+fn env_suggest(env: &Env, key: &str) -> Option<String> {
+    let max_dist = key.chars().count() / 3;
+    env_keys(env)
+        .into_iter()
+        .map(|k| (edit_distance(key, &k), !is_subsequence(key, &k), k))
+        .filter(|(d, _, _)| *d > 0 && *d <= max_dist)
+        .min()
+        .map(|(_, _, k)| k)
+}
+
+fn is_subsequence(a: &str, b: &str) -> bool {
+    let mut b = b.chars();
+    a.chars().all(|ca| b.any(|cb| ca == cb))
+}
+
+// Levenshtein distance, counting a swap of adjacent characters as one edit.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let mut d = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in d.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    d[0] = (0..=b.len()).collect();
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = if a[i - 1] == b[j - 1] { 0 } else { 1 };
+            d[i][j] = (d[i - 1][j] + 1)
+                .min(d[i][j - 1] + 1)
+                .min(d[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                d[i][j] = d[i][j].min(d[i - 2][j - 2] + 1);
+            }
         }
     }
+    d[a.len()][b.len()]
 }
 
 pub fn env_find_repl(env: &Env) -> Env {
@@ -73,10 +143,17 @@ pub fn env_set(env: &Env, key: &MalVal, val: MalVal) -> MalRet {
             env_sets(env, s, val.clone());
             Ok(val)
         }
-        _ => error("Env.set called with non-Str"),
+        _ => type_error("Env.set called with non-Str"),
     }
 }
 
 pub fn env_sets(env: &Env, key: &str, val: MalVal) {
     env.data.borrow_mut().insert(key.to_string(), val);
 }
+
+// Binds key to a Rust closure, which may capture the host program's own
+// state. This is how a program embedding mal adds functions of its own.
+#[allow(dead_code)]
+pub fn env_set_native<F: Fn(MalArgs) -> MalRet + 'static>(env: &Env, key: &str, f: F) {
+    env_sets(env, key, func(f));
+}
@@ -1,11 +1,15 @@
 use crate::types::MalVal;
-use crate::types::MalVal::{Atom, Bool, Func, Hash, Int, List, MalFunc, Nil, Str, Sym, Vector};
+use crate::types::MalVal::{
+    Atom, Bool, Float, Func, Hash, Int, List, MalFunc, Nil, Pattern, Set, Str, Sym, Vector,
+};
 
 fn escape_str(s: &str) -> String {
     s.chars()
         .map(|c| match c {
             '"' => "\\\"".to_string(),
             '\n' => "\\n".to_string(),
+            '\t' => "\\t".to_string(),
+            '\r' => "\\r".to_string(),
             '\\' => "\\\\".to_string(),
             _ => c.to_string(),
         })
@@ -13,6 +17,21 @@ fn escape_str(s: &str) -> String {
         .join("")
 }
 
+// A pattern prints as written, except that a quote it does not already
+// escape gets a backslash so that the literal reads back.
+fn escape_pattern(s: &str) -> String {
+    let mut res = String::new();
+    let mut escaped = false;
+    for c in s.chars() {
+        if c == '"' && !escaped {
+            res.push('\\');
+        }
+        escaped = c == '\\' && !escaped;
+        res.push(c);
+    }
+    res
+}
+
 impl MalVal {
     pub fn pr_str(&self, print_readably: bool) -> String {
         match self {
@@ -20,7 +39,12 @@ impl MalVal {
             Bool(true) => String::from("true"),
             Bool(false) => String::from("false"),
             Int(i) => format!("{}", i),
-            //Float(f)    => format!("{}", f),
+            // Debug would write inf and NaN, which read back as symbols.
+            Float(f) if f.is_nan() => String::from("##NaN"),
+            Float(f) if f.is_infinite() => String::from(if *f > 0.0 { "##Inf" } else { "##-Inf" }),
+            // Debug always writes a dot or an exponent, as in 2.0 or
+            // 1e-7, so the number reads back as a float.
+            Float(f) => format!("{:?}", f),
             Str(s) => {
                 if let Some(keyword) = s.strip_prefix('\u{29e}') {
                     format!(":{}", keyword)
@@ -36,19 +60,73 @@ impl MalVal {
             Hash(hm, _) => {
                 let l: Vec<MalVal> = hm
                     .iter()
-                    .flat_map(|(k, v)| vec![Str(k.to_string()), v.clone()])
+                    .flat_map(|(k, v)| vec![k.clone(), v.clone()])
                     .collect();
                 pr_seq(&l, print_readably, "{", "}", " ")
             }
+            Set(s, _) => {
+                let l: Vec<MalVal> = s.iter().cloned().collect();
+                pr_seq(&l, print_readably, "#{", "}", " ")
+            }
+            Pattern(re) if print_readably => format!("#\"{}\"", escape_pattern(re.as_str())),
+            Pattern(re) => re.as_str().to_string(),
             Func(_, _) => String::from("#<builtin>"),
             MalFunc {
                 ast: a, params: p, ..
-            } => format!("(fn* {} {})", p.pr_str(true), a.pr_str(true)),
+            } => match (&**p, &**a) {
+                (Nil, List(clauses, _)) => pr_seq(clauses, true, "(fn* ", ")", " "),
+                _ => format!("(fn* {} {})", p.pr_str(true), a.pr_str(true)),
+            },
             Atom(a) => format!("(atom {})", a.borrow().pr_str(true)),
         }
     }
 }
 
+impl MalVal {
+    // Like pr_str, but collections that contain other collections are
+    // broken over several lines, each element aligned one column past
+    // the opening delimiter. `indent` is the column the value starts at.
+    pub fn pr_pretty(&self, print_readably: bool, indent: usize) -> String {
+        match self {
+            List(l, _) if has_nested(l) => pr_pretty_seq(l, print_readably, indent, "(", ")"),
+            Vector(l, _) if has_nested(l) => pr_pretty_seq(l, print_readably, indent, "[", "]"),
+            Hash(hm, _) if has_nested(&hm.values().cloned().collect::<Vec<MalVal>>()) => {
+                let pad = " ".repeat(indent + 1);
+                let entries: Vec<String> = hm
+                    .iter()
+                    .map(|(k, v)| {
+                        let k = k.pr_str(print_readably);
+                        let v = v.pr_pretty(print_readably, indent + 1 + k.chars().count() + 1);
+                        format!("{} {}", k, v)
+                    })
+                    .collect();
+                format!("{{{}}}", entries.join(&format!("\n{}", pad)))
+            }
+            _ => self.pr_str(print_readably),
+        }
+    }
+}
+
+fn has_nested(seq: &[MalVal]) -> bool {
+    seq.iter()
+        .any(|mv| matches!(mv, List(_, _) | Vector(_, _) | Hash(_, _)))
+}
+
+fn pr_pretty_seq(
+    seq: &[MalVal],
+    print_readably: bool,
+    indent: usize,
+    start: &str,
+    end: &str
+) -> String {
+    let strs: Vec<String> = seq
+        .iter()
+        .map(|x| x.pr_pretty(print_readably, indent + 1))
+        .collect();
+    let join = format!("\n{}", " ".repeat(indent + 1));
+    format!("{}{}{}", start, strs.join(&join), end)
+}
+
 pub fn pr_seq(
     seq: &[MalVal],
     print_readably: bool,
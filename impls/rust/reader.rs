@@ -1,79 +1,216 @@
-use regex::{Captures, Regex};
+use regex::Regex;
+use std::io::BufRead;
+use std::mem;
 use std::rc::Rc;
 
-use crate::types::MalErr::ErrString;
-use crate::types::MalVal::{Bool, Int, List, Nil, Str, Sym, Vector};
-use crate::types::{error, hash_map, MalErr, MalRet, MalVal};
+use crate::types::MalErr::{ErrString, ReaderError};
+use crate::types::MalVal::{Bool, Float, Int, List, Nil, Str, Sym, Vector};
+use crate::types::{
+    hash_map, hash_set, pattern, reader_error, MalArgs, MalErr, MalRet, MalSet, MalVal,
+};
+
+// A token with the line and column it starts at, both counted from 1.
+#[derive(Debug, Clone)]
+struct Token {
+    text: String,
+    line: usize,
+    col: usize,
+}
 
 #[derive(Debug, Clone)]
 struct Reader {
-    tokens: Vec<String>,
+    tokens: Vec<Token>,
     pos: usize,
 }
 
 impl Reader {
-    fn next(&mut self) -> Result<String, MalErr> {
+    fn next(&mut self) -> Result<Token, MalErr> {
         self.pos += 1;
         Ok(self
             .tokens
             .get(self.pos - 1)
-            .ok_or_else(|| ErrString("underflow".to_string()))?
-            .to_string())
+            .ok_or_else(|| ReaderError("underflow".to_string()))?
+            .clone())
     }
-    fn peek(&self) -> Result<String, MalErr> {
+    fn peek(&self) -> Result<Token, MalErr> {
         Ok(self
             .tokens
             .get(self.pos)
-            .ok_or_else(|| ErrString("underflow".to_string()))?
-            .to_string())
+            .ok_or_else(|| ReaderError("underflow".to_string()))?
+            .clone())
+    }
+}
+
+fn error_at(token: &Token, msg: &str) -> MalRet {
+    reader_error(&format!("{} at line {}, column {}", msg, token.line, token.col))
+}
+
+// Moves line and col past text. A line ends at \n, \r\n or a bare \r.
+fn advance(line: &mut usize, col: &mut usize, text: &str) {
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\r' if chars.peek() == Some(&'\n') => (),
+            '\r' | '\n' => {
+                *line += 1;
+                *col = 1;
+            }
+            _ => *col += 1,
+        }
     }
 }
 
-fn tokenize(str: &str) -> Vec<String> {
+// Tokens of str, comments included, where str starts at the given line
+// and column.
+fn scan(str: &str, mut line: usize, mut col: usize) -> Vec<Token> {
     lazy_static! {
         static ref RE: Regex = Regex::new(
-            r###"[\s,]*(~@|[\[\]{}()'`~^@]|"(?:\\.|[^\\"])*"?|;.*|[^\s\[\]{}('"`,;)]+)"###
+            r###"[\s,]*(~@|#[_{]|#"(?:\\.|[^\\"])*"?|[\[\]{}()'`~^@]|"(?:\\.|[^\\"])*"?|;[^\r\n]*|[^\s\[\]{}('"`,;)]+)"###
         )
         .unwrap();
     }
 
     let mut res = vec![];
+    let mut last = 0;
     for cap in RE.captures_iter(str) {
-        if cap[1].starts_with(';') {
-            continue;
-        }
-        res.push(String::from(&cap[1]));
+        let m = cap.get(1).unwrap();
+        advance(&mut line, &mut col, &str[last..m.start()]);
+        last = m.start();
+        res.push(Token {
+            text: m.as_str().to_string(),
+            line,
+            col,
+        });
     }
     res
 }
 
-fn unescape_str(s: &str) -> String {
+fn tokenize(str: &str, line: usize, col: usize) -> Vec<Token> {
+    scan(str, line, col).into_iter().filter(|t| !t.text.starts_with(';')).collect()
+}
+
+// The comments that reading str skips, each with its line and column.
+pub fn read_comments(str: &str) -> Vec<(String, usize, usize)> {
+    scan(str, 1, 1)
+        .into_iter()
+        .filter(|t| t.text.starts_with(';'))
+        .map(|t| (t.text, t.line, t.col))
+        .collect()
+}
+
+// Like tokenize, but keeps whitespace and comments as tokens of their
+// own so that joining the tokens gives back the source, for tools such
+// as formatters.
+pub fn tokenize_source(str: &str) -> Vec<String> {
     lazy_static! {
-        static ref RE: Regex = Regex::new(r#"\\(.)"#).unwrap();
+        static ref RE: Regex = Regex::new(
+            r###"[\s,]+|~@|#[_{]|#"(?:\\.|[^\\"])*"?|[\[\]{}()'`~^@]|"(?:\\.|[^\\"])*"?|;[^\r\n]*|[^\s\[\]{}('"`,;)]+"###
+        )
+        .unwrap();
+    }
+
+    RE.find_iter(str).map(|m| m.as_str().to_string()).collect()
+}
+
+// Escapes are \n, \t, \r, \", \\ and \u{...} with one to six hex
+// digits; any other is an error naming it.
+fn unescape_str(s: &str) -> Result<String, String> {
+    let mut res = String::new();
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            res.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => res.push('\n'),
+            Some('t') => res.push('\t'),
+            Some('r') => res.push('\r'),
+            Some(e @ '"') | Some(e @ '\\') => res.push(e),
+            Some('u') => {
+                let rest = chars.as_str();
+                let hex = match (rest.strip_prefix('{'), rest.find('}')) {
+                    (Some(_), Some(end)) => &rest[1..end],
+                    _ => return Err("invalid unicode escape: \\u".to_string()),
+                };
+                let u = match hex.len() {
+                    1..=6 if hex.chars().all(|h| h.is_ascii_hexdigit()) => {
+                        u32::from_str_radix(hex, 16).ok().and_then(std::char::from_u32)
+                    }
+                    _ => None,
+                };
+                match u {
+                    Some(u) => res.push(u),
+                    None => return Err(format!("invalid unicode escape: \\u{{{}}}", hex)),
+                }
+                chars = rest[hex.len() + 2..].chars();
+            }
+            Some(e) => return Err(format!("invalid escape sequence: \\{}", e)),
+            None => return Err("invalid escape sequence: \\".to_string()),
+        }
+    }
+    Ok(res)
+}
+
+// Drops the underscores in a literal like 1_000, which may only sit
+// singly between two digits.
+fn strip_separators(s: &str) -> Option<String> {
+    if s.starts_with('_') || s.ends_with('_') || s.contains("__") {
+        return None;
     }
-    RE.replace_all(s, |caps: &Captures| {
-        if &caps[1] == "n" { "\n" } else { &caps[1] }.to_string()
-    })
-    .to_string()
+    Some(s.replace('_', ""))
 }
 
 fn read_atom(rdr: &mut Reader) -> MalRet {
     lazy_static! {
-        static ref INT_RE: Regex = Regex::new(r"^-?[0-9]+$").unwrap();
+        static ref INT_RE: Regex = Regex::new(r"^-?[0-9][0-9_]*$").unwrap();
+        static ref RADIX_RE: Regex = Regex::new(r"^(-?)0([xXoObB])(.*)$").unwrap();
+        static ref FLOAT_RE: Regex = Regex::new(r"^-?[0-9]+(\.[0-9]+)?([eE][-+]?[0-9]+)?$").unwrap();
+        // Digits then a dot or exponent: meant as a number, not a symbol.
+        static ref NUM_RE: Regex = Regex::new(r"^-?[0-9][0-9_]*[.eE]").unwrap();
         static ref STR_RE: Regex = Regex::new(r#""(?:\\.|[^\\"])*""#).unwrap();
     }
-    let token = rdr.next()?;
+    let tok = rdr.next()?;
+    let token = &tok.text;
     match &token[..] {
         "nil" => Ok(Nil),
         "false" => Ok(Bool(false)),
         "true" => Ok(Bool(true)),
         _ => {
-            if INT_RE.is_match(&token) {
-                Ok(Int(token.parse().unwrap()))
-            } else if STR_RE.is_match(&token) {
-                Ok(Str(unescape_str(&token[1..token.len() - 1])))
+            if INT_RE.is_match(token) {
+                match strip_separators(token).map(|t| t.parse()) {
+                    Some(Ok(i)) => Ok(Int(i)),
+                    Some(Err(_)) => error_at(&tok, &format!("integer literal out of range: {}", token)),
+                    None => error_at(&tok, &format!("invalid integer literal: {}", token)),
+                }
+            } else if let Some(caps) = RADIX_RE.captures(token) {
+                let (radix, kind) = match &caps[2] {
+                    "x" | "X" => (16, "hexadecimal"),
+                    "o" | "O" => (8, "octal"),
+                    _ => (2, "binary"),
+                };
+                let digits = match strip_separators(&caps[3]) {
+                    Some(d) if !d.is_empty() && d.chars().all(|c| c.is_digit(radix)) => d,
+                    _ => return error_at(&tok, &format!("invalid {} literal: {}", kind, token)),
+                };
+                match i64::from_str_radix(&format!("{}{}", &caps[1], digits), radix) {
+                    Ok(i) => Ok(Int(i)),
+                    Err(_) => error_at(&tok, &format!("integer literal out of range: {}", token)),
+                }
+            } else if FLOAT_RE.is_match(token) {
+                match token.parse::<f64>() {
+                    Ok(f) if f.is_finite() => Ok(Float(f)),
+                    _ => error_at(&tok, &format!("float literal out of range: {}", token)),
+                }
+            } else if NUM_RE.is_match(token) {
+                error_at(&tok, &format!("invalid number literal: {}", token))
+            } else if STR_RE.is_match(token) {
+                match unescape_str(&token[1..token.len() - 1]) {
+                    Ok(s) => Ok(Str(s)),
+                    Err(msg) => error_at(&tok, &msg),
+                }
             } else if token.starts_with('\"') {
-                error("expected '\"', got EOF")
+                error_at(&tok, "expected '\"', got EOF")
             } else if let Some(keyword) = token.strip_prefix(':') {
                 Ok(Str(format!("\u{29e}{}", keyword)))
             } else {
@@ -83,74 +220,327 @@ fn read_atom(rdr: &mut Reader) -> MalRet {
     }
 }
 
-fn read_seq(rdr: &mut Reader, end: &str) -> MalRet {
+// The forms up to end, which closes the token read first.
+fn read_seq(rdr: &mut Reader, end: &str) -> Result<Vec<MalVal>, MalErr> {
     let mut seq: Vec<MalVal> = vec![];
-    rdr.next()?;
+    let open = rdr.next()?;
     loop {
+        skip_discarded(rdr)?;
         let token = match rdr.peek() {
             Ok(t) => t,
-            Err(_) => return error(&format!("expected '{}', got EOF", end)),
+            Err(_) => {
+                return Err(ReaderError(format!(
+                    "expected '{}', got EOF (unclosed '{}' at line {}, column {})",
+                    end, open.text, open.line, open.col
+                )))
+            }
         };
-        if token == end {
+        if token.text == end {
             break;
         }
         seq.push(read_form(rdr)?)
     }
     let _ = rdr.next();
-    match end {
-        ")" => Ok(list!(seq)),
-        "]" => Ok(vector!(seq)),
-        "}" => hash_map(seq),
-        _ => error("read_seq unknown end value"),
+    Ok(seq)
+}
+
+// Keys are read as written and only eval evaluates them, so two key
+// forms that look alike, such as (gensym) twice, could give two entries
+// that the map read here cannot hold. As in Clojure, such a literal is
+// an error rather than something the reader quietly merges or rewrites.
+fn read_map(open: &Token, kvs: MalArgs) -> MalRet {
+    let mut keys = MalSet::default();
+    for k in kvs.iter().step_by(2) {
+        if !keys.insert(k.clone()) {
+            return error_at(open, &format!("duplicate key: {}", k.pr_str(true)));
+        }
+    }
+    hash_map(kvs)
+}
+
+// Reads the form that a # dispatch token, still unread, begins.
+type DispatchFn = fn(&mut Reader, &Token) -> MalRet;
+
+// The # dispatch forms, by how their token starts. Another one needs
+// an entry here and, unless its token already reads as one (as #tag
+// would), a case in the tokenizer. #_ is not here: it reads no form,
+// and skip_discarded drops it wherever a form may start.
+const DISPATCH: &[(&str, DispatchFn)] = &[
+    ("#{", read_set),
+    ("#\"", read_pattern),
+    ("##", read_symbolic_value),
+];
+
+fn read_set(rdr: &mut Reader, _token: &Token) -> MalRet {
+    hash_set(read_seq(rdr, "}")?)
+}
+
+// The floats that have no digits to write them with, as the printer
+// writes them.
+fn read_symbolic_value(rdr: &mut Reader, token: &Token) -> MalRet {
+    let _ = rdr.next();
+    match token.text.as_str() {
+        "##Inf" => Ok(Float(f64::INFINITY)),
+        "##-Inf" => Ok(Float(f64::NEG_INFINITY)),
+        "##NaN" => Ok(Float(f64::NAN)),
+        t => error_at(token, &format!("unknown symbolic value: {}", t)),
     }
 }
 
+// The text between the quotes is the regular expression as is: a
+// backslash escapes for the regex engine, not for the reader.
+fn read_pattern(rdr: &mut Reader, token: &Token) -> MalRet {
+    let _ = rdr.next();
+    if unterminated(&token.text) {
+        return error_at(token, "expected '\"', got EOF");
+    }
+    match pattern(&token.text[2..token.text.len() - 1]) {
+        Err(ErrString(msg)) => error_at(token, &msg),
+        re => re,
+    }
+}
+
+// Whether t is a string or pattern literal that is missing its
+// closing quote.
+fn unterminated(t: &str) -> bool {
+    lazy_static! {
+        static ref STR_RE: Regex = Regex::new(r#"^"(?:\\.|[^\\"])*"$"#).unwrap();
+    }
+    let s = t.strip_prefix('#').unwrap_or(t);
+    s.starts_with('"') && !STR_RE.is_match(s)
+}
+
+// The form the reader macro at token applies to, which must not be
+// missing.
+fn read_operand(rdr: &mut Reader, token: &Token, name: &str) -> MalRet {
+    skip_discarded(rdr)?;
+    if rdr.peek().is_err() {
+        return error_at(token, &format!("{}: expected a form", name));
+    }
+    read_form(rdr)
+}
+
+// Drops any #_ at the front of rdr along with the form it discards.
+fn skip_discarded(rdr: &mut Reader) -> Result<(), MalErr> {
+    while let Ok(token) = rdr.peek() {
+        if token.text != "#_" {
+            break;
+        }
+        rdr.next()?;
+        read_operand(rdr, &token, "#_")?;
+    }
+    Ok(())
+}
+
 fn read_form(rdr: &mut Reader) -> MalRet {
     let token = rdr.peek()?;
-    match &token[..] {
+    match &token.text[..] {
         "'" => {
             let _ = rdr.next();
-            Ok(list![Sym("quote".to_string()), read_form(rdr)?])
+            Ok(list![Sym("quote".to_string()), read_operand(rdr, &token, "quote")?])
         }
         "`" => {
             let _ = rdr.next();
-            Ok(list![Sym("quasiquote".to_string()), read_form(rdr)?])
+            Ok(list![Sym("quasiquote".to_string()), read_operand(rdr, &token, "quasiquote")?])
         }
         "~" => {
             let _ = rdr.next();
-            Ok(list![Sym("unquote".to_string()), read_form(rdr)?])
+            Ok(list![Sym("unquote".to_string()), read_operand(rdr, &token, "unquote")?])
         }
         "~@" => {
             let _ = rdr.next();
-            Ok(list![Sym("splice-unquote".to_string()), read_form(rdr)?])
+            Ok(list![Sym("splice-unquote".to_string()), read_operand(rdr, &token, "splice-unquote")?])
         }
         "^" => {
             let _ = rdr.next();
-            let meta = read_form(rdr)?;
-            Ok(list![Sym("with-meta".to_string()), read_form(rdr)?, meta])
+            let meta = read_operand(rdr, &token, "with-meta")?;
+            Ok(list![Sym("with-meta".to_string()), read_operand(rdr, &token, "with-meta")?, meta])
         }
         "@" => {
             let _ = rdr.next();
-            Ok(list![Sym("deref".to_string()), read_form(rdr)?])
-        }
-        ")" => error("unexpected ')'"),
-        "(" => read_seq(rdr, ")"),
-        "]" => error("unexpected ']'"),
-        "[" => read_seq(rdr, "]"),
-        "}" => error("unexpected '}'"),
-        "{" => read_seq(rdr, "}"),
+            Ok(list![Sym("deref".to_string()), read_operand(rdr, &token, "deref")?])
+        }
+        ")" => error_at(&token, "unexpected ')'"),
+        "(" => Ok(list!(read_seq(rdr, ")")?)),
+        "]" => error_at(&token, "unexpected ']'"),
+        "[" => Ok(vector!(read_seq(rdr, "]")?)),
+        "}" => error_at(&token, "unexpected '}'"),
+        "{" => read_map(&token, read_seq(rdr, "}")?),
+        t if t.starts_with('#') => match DISPATCH.iter().find(|(start, _)| t.starts_with(start)) {
+            Some((_, read)) => read(rdr, &token),
+            None => read_atom(rdr),
+        },
         _ => read_atom(rdr),
     }
 }
 
+// Yields the forms of a stream one at a time, reading only as many
+// lines as are needed to complete the next form.
+pub struct FormReader<R> {
+    input: R,
+    // Trailing text that ends inside a string literal.
+    pending: String,
+    // Where pending starts in the input.
+    line: usize,
+    col: usize,
+    tokens: Vec<Token>,
+    // Progress of the scan for the end of the first form in tokens.
+    scan: usize,
+    depth: usize,
+    need: usize,
+    eof: bool,
+}
+
+pub fn read_forms<R: BufRead>(input: R) -> FormReader<R> {
+    FormReader {
+        input,
+        pending: String::new(),
+        line: 1,
+        col: 1,
+        tokens: vec![],
+        scan: 0,
+        depth: 0,
+        need: 1,
+        eof: false,
+    }
+}
+
+impl<R: BufRead> FormReader<R> {
+    // Index of the token that completes the first form, once it has
+    // been read. Malformed input also ends a form so read_form can
+    // report it.
+    fn form_end(&mut self) -> Option<usize> {
+        while self.scan < self.tokens.len() {
+            let i = self.scan;
+            self.scan += 1;
+            match &self.tokens[i].text[..] {
+                "(" | "[" | "{" | "#{" => {
+                    self.depth += 1;
+                    continue;
+                }
+                ")" | "]" | "}" if self.depth > 1 => {
+                    self.depth -= 1;
+                    continue;
+                }
+                ")" | "]" | "}" => self.depth = 0,
+                "'" | "`" | "~" | "~@" | "@" => continue,
+                "^" | "#_" if self.depth == 0 => {
+                    self.need += 1;
+                    continue;
+                }
+                _ if self.depth > 0 => continue,
+                _ => (),
+            }
+            self.need -= 1;
+            if self.need == 0 {
+                return Some(i);
+            }
+        }
+        None
+    }
+
+    // None when the tokens hold nothing but #_ forms.
+    fn take_form(&mut self, end: usize) -> Option<MalRet> {
+        let rest = self.tokens.split_off(end);
+        let tokens = mem::replace(&mut self.tokens, rest);
+        self.scan = 0;
+        self.depth = 0;
+        self.need = 1;
+        let mut rdr = Reader { pos: 0, tokens };
+        match skip_discarded(&mut rdr) {
+            Ok(()) if rdr.peek().is_err() => None,
+            Ok(()) => Some(read_form(&mut rdr)),
+            Err(e) => Some(Err(e)),
+        }
+    }
+
+    fn fill(&mut self) -> Result<(), MalErr> {
+        let mut line = String::new();
+        match self.input.read_line(&mut line) {
+            Ok(0) => {
+                self.eof = true;
+                let rest = mem::take(&mut self.pending);
+                self.tokens.extend(tokenize(&rest, self.line, self.col));
+            }
+            Ok(_) => {
+                self.pending.push_str(&line);
+                let mut tokens = tokenize(&self.pending, self.line, self.col);
+                // A string or pattern literal may continue on the next line.
+                match tokens.last() {
+                    Some(t) if unterminated(&t.text) => {
+                        let start = self.pending.rfind(t.text.as_str()).unwrap_or(0);
+                        self.pending = self.pending.split_off(start);
+                        self.line = t.line;
+                        self.col = t.col;
+                        tokens.pop();
+                    }
+                    _ => {
+                        advance(&mut self.line, &mut self.col, &self.pending);
+                        self.pending.clear();
+                    }
+                }
+                self.tokens.extend(tokens);
+            }
+            Err(e) => {
+                self.eof = true;
+                return Err(ErrString(e.to_string()));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<R: BufRead> Iterator for FormReader<R> {
+    type Item = MalRet;
+
+    fn next(&mut self) -> Option<MalRet> {
+        loop {
+            if let Some(end) = self.form_end() {
+                match self.take_form(end + 1) {
+                    Some(form) => return Some(form),
+                    None => continue,
+                }
+            }
+            if self.eof {
+                if self.tokens.is_empty() {
+                    return None;
+                }
+                let end = self.tokens.len();
+                return self.take_form(end);
+            }
+            if let Err(e) = self.fill() {
+                return Some(Err(e));
+            }
+        }
+    }
+}
+
 pub fn read_str(str: &str) -> MalRet {
-    let tokens = tokenize(str);
-    //println!("tokens: {:?}", tokens);
-    if tokens.is_empty() {
-        return error("no input");
-    }
-    read_form(&mut Reader {
-        pos: 0,
-        tokens
-    })
+    match read_forms(str.as_bytes()).next() {
+        Some(form) => form,
+        None => reader_error("no input"),
+    }
+}
+
+// Whether str stops partway through a form, inside a list, vector, map
+// or string or just after a reader macro, so that more input could
+// complete it. Malformed input is not incomplete; reading it reports
+// the error.
+#[allow(dead_code)]
+pub fn is_incomplete(str: &str) -> bool {
+    let tokens = tokenize(str, 1, 1);
+    let mut depth = 0;
+    for t in tokens.iter() {
+        match &t.text[..] {
+            "(" | "[" | "{" | "#{" => depth += 1,
+            ")" | "]" | "}" if depth == 0 => return false,
+            ")" | "]" | "}" => depth -= 1,
+            _ => (),
+        }
+    }
+    match tokens.last().map(|t| &t.text[..]) {
+        Some(t) if unterminated(t) => true,
+        Some("'") | Some("`") | Some("~") | Some("~@") | Some("@") | Some("^") | Some("#_") => true,
+        _ => depth > 0,
+    }
 }
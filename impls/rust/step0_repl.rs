@@ -1,3 +1,5 @@
+use std::io;
+
 extern crate rustyline;
 
 use rustyline::error::ReadlineError;
@@ -22,6 +24,11 @@ fn main() {
             }
             Err(ReadlineError::Interrupted) => continue,
             Err(ReadlineError::Eof) => break,
+            // A line that is not valid UTF-8 is dropped, not the session.
+            Err(ReadlineError::Io(err)) if err.kind() == io::ErrorKind::InvalidData => {
+                println!("Error: {}", err);
+                continue;
+            }
             Err(err) => {
                 println!("Error: {:?}", err);
                 break;
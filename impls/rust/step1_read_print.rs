@@ -1,6 +1,9 @@
+use std::io;
+
 #[macro_use]
 extern crate lazy_static;
 extern crate fnv;
+extern crate indexmap;
 extern crate itertools;
 extern crate regex;
 
@@ -12,7 +15,9 @@ use rustyline::Editor;
 #[allow(dead_code)]
 mod types;
 use crate::types::format_error;
+#[allow(dead_code)]
 mod printer;
+#[allow(dead_code)]
 mod reader;
 // TODO: figure out a way to avoid including env
 #[allow(dead_code)]
@@ -42,6 +47,11 @@ fn main() {
             }
             Err(ReadlineError::Interrupted) => continue,
             Err(ReadlineError::Eof) => break,
+            // A line that is not valid UTF-8 is dropped, not the session.
+            Err(ReadlineError::Io(err)) if err.kind() == io::ErrorKind::InvalidData => {
+                println!("Error: {}", err);
+                continue;
+            }
             Err(err) => {
                 println!("Error: {:?}", err);
                 break;
@@ -1,3 +1,4 @@
+use std::io;
 use std::rc::Rc;
 //use std::collections::HashMap;
 use fnv::FnvHashMap;
@@ -5,6 +6,7 @@ use fnv::FnvHashMap;
 #[macro_use]
 extern crate lazy_static;
 extern crate fnv;
+extern crate indexmap;
 extern crate itertools;
 extern crate regex;
 
@@ -15,10 +17,14 @@ use rustyline::Editor;
 #[macro_use]
 #[allow(dead_code)]
 mod types;
-use crate::types::MalErr::ErrString;
-use crate::types::MalVal::{Hash, Int, List, Nil, Sym, Vector};
-use crate::types::{error, format_error, func, MalArgs, MalErr, MalRet, MalVal};
+use crate::types::MalErr::UnknownSymbol;
+use crate::types::MalVal::{Hash, Int, List, Nil, Set, Sym, Vector};
+use crate::types::{
+    check_key, error, format_error, func, hash_set, MalArgs, MalErr, MalMap, MalRet, MalVal,
+};
+#[allow(dead_code)]
 mod printer;
+#[allow(dead_code)]
 mod reader;
 // TODO: figure out a way to avoid including env
 #[allow(dead_code)]
@@ -37,7 +43,7 @@ fn eval(ast: &MalVal, env: &Env) -> MalRet {
     match ast {
         Sym(sym) => Ok(env
             .get(sym)
-            .ok_or_else(|| ErrString(format!("'{}' not found", sym)))?
+            .ok_or_else(|| UnknownSymbol(format!("'{}' not found", sym)))?
             .clone()),
         Vector(v, _) => {
             let mut lst: MalArgs = vec![];
@@ -47,12 +53,23 @@ fn eval(ast: &MalVal, env: &Env) -> MalRet {
             Ok(vector!(lst))
         }
         Hash(hm, _) => {
-            let mut new_hm: FnvHashMap<String, MalVal> = FnvHashMap::default();
+            let mut new_hm = MalMap::default();
+            // Keys are evaluated as well, and ones that come out equal
+            // collapse into one entry with the later value.
             for (k, v) in hm.iter() {
-                new_hm.insert(k.to_string(), eval(v, env)?);
+                let k = eval(k, env)?;
+                check_key(&k)?;
+                new_hm.insert(k, eval(v, env)?);
             }
             Ok(Hash(Rc::new(new_hm), Rc::new(Nil)))
         }
+        Set(s, _) => {
+            let mut items: MalArgs = vec![];
+            for a in s.iter() {
+                items.push(eval(a, env)?);
+            }
+            hash_set(items)
+        }
         List(l, _) => {
             if l.is_empty() {
                 return Ok(ast.clone());
@@ -80,13 +97,23 @@ fn rep(str: &str, env: &Env) -> Result<String, MalErr> {
     Ok(print(&exp))
 }
 
-fn int_op(op: fn(i64, i64) -> i64, a: MalArgs) -> MalRet {
-    match (a[0].clone(), a[1].clone()) {
-        (Int(a0), Int(a1)) => Ok(Int(op(a0, a1))),
+fn int_op(op: fn(i64, i64) -> Option<i64>, a: MalArgs) -> MalRet {
+    match a[..] {
+        [Int(a0), Int(a1)] => match op(a0, a1) {
+            Some(r) => Ok(Int(r)),
+            None => error("integer overflow"),
+        },
         _ => error("invalid int_op args"),
     }
 }
 
+fn divide(a: MalArgs) -> MalRet {
+    match a[..] {
+        [Int(_), Int(0)] => error("division by zero"),
+        _ => int_op(i64::checked_div, a),
+    }
+}
+
 fn main() {
     // `()` can be used when no completer is required
     let mut rl = Editor::<(), rustyline::history::DefaultHistory>::new().unwrap();
@@ -95,10 +122,10 @@ fn main() {
     }
 
     let mut repl_env = Env::default();
-    repl_env.insert("+".to_string(), func(|a: MalArgs| int_op(|i, j| i + j, a)));
-    repl_env.insert("-".to_string(), func(|a: MalArgs| int_op(|i, j| i - j, a)));
-    repl_env.insert("*".to_string(), func(|a: MalArgs| int_op(|i, j| i * j, a)));
-    repl_env.insert("/".to_string(), func(|a: MalArgs| int_op(|i, j| i / j, a)));
+    repl_env.insert("+".to_string(), func(|a: MalArgs| int_op(i64::checked_add, a)));
+    repl_env.insert("-".to_string(), func(|a: MalArgs| int_op(i64::checked_sub, a)));
+    repl_env.insert("*".to_string(), func(|a: MalArgs| int_op(i64::checked_mul, a)));
+    repl_env.insert("/".to_string(), func(divide));
 
     loop {
         let readline = rl.readline("user> ");
@@ -115,6 +142,11 @@ fn main() {
             }
             Err(ReadlineError::Interrupted) => continue,
             Err(ReadlineError::Eof) => break,
+            // A line that is not valid UTF-8 is dropped, not the session.
+            Err(ReadlineError::Io(err)) if err.kind() == io::ErrorKind::InvalidData => {
+                println!("Error: {}", err);
+                continue;
+            }
             Err(err) => {
                 println!("Error: {:?}", err);
                 break;
@@ -1,11 +1,12 @@
+use std::io;
 use std::rc::Rc;
 //use std::collections::HashMap;
-use fnv::FnvHashMap;
 use itertools::Itertools;
 
 #[macro_use]
 extern crate lazy_static;
 extern crate fnv;
+extern crate indexmap;
 extern crate itertools;
 extern crate regex;
 
@@ -16,12 +17,18 @@ use rustyline::Editor;
 #[macro_use]
 #[allow(dead_code)]
 mod types;
-use crate::types::MalVal::{Bool, Hash, Int, List, Nil, Sym, Vector};
-use crate::types::{error, format_error, func, MalArgs, MalErr, MalRet, MalVal};
+use crate::types::MalVal::{Hash, Int, List, Nil, Set, Sym, Vector};
+use crate::types::{
+    check_arity, check_key, error, format_error, func, hash_set, MalArgs, MalErr, MalMap, MalRet,
+    MalVal,
+};
+#[allow(dead_code)]
 mod env;
+#[allow(dead_code)]
 mod printer;
+#[allow(dead_code)]
 mod reader;
-use crate::env::{env_get, env_new, env_set, env_sets, Env};
+use crate::env::{env_get, env_new, env_not_found, env_set, env_sets, Env};
 
 // read
 fn read(str: &str) -> MalRet {
@@ -31,13 +38,13 @@ fn read(str: &str) -> MalRet {
 // eval
 fn eval(ast: &MalVal, env: &Env) -> MalRet {
     match env_get(env, "DEBUG-EVAL") {
-        None | Some(Bool(false)) | Some(Nil) => (),
-        _ => println!("EVAL: {}", print(ast)),
+        Some(v) if v.truthy() => println!("EVAL: {}", print(ast)),
+        _ => (),
     }
     match ast {
         Sym(s) => match env_get(env, s) {
             Some(r) => Ok(r),
-            None => error (&format!("'{}' not found", s)),
+            None => env_not_found(env, s),
         }
         Vector(v, _) => {
             let mut lst: MalArgs = vec![];
@@ -47,12 +54,23 @@ fn eval(ast: &MalVal, env: &Env) -> MalRet {
             Ok(vector!(lst))
         }
         Hash(hm, _) => {
-            let mut new_hm: FnvHashMap<String, MalVal> = FnvHashMap::default();
+            let mut new_hm = MalMap::default();
+            // Keys are evaluated as well, and ones that come out equal
+            // collapse into one entry with the later value.
             for (k, v) in hm.iter() {
-                new_hm.insert(k.to_string(), eval(v, env)?);
+                let k = eval(k, env)?;
+                check_key(&k)?;
+                new_hm.insert(k, eval(v, env)?);
             }
             Ok(Hash(Rc::new(new_hm), Rc::new(Nil)))
         }
+        Set(s, _) => {
+            let mut items: MalArgs = vec![];
+            for a in s.iter() {
+                items.push(eval(a, env)?);
+            }
+            hash_set(items)
+        }
         List(l, _) => {
             if l.is_empty() {
                 return Ok(ast.clone());
@@ -60,9 +78,11 @@ fn eval(ast: &MalVal, env: &Env) -> MalRet {
             let a0 = &l[0];
             match a0 {
                 Sym(a0sym) if a0sym == "def!" => {
+                    check_arity("def!", l.len() - 1, 2, Some(2))?;
                     env_set(env, &l[1], eval(&l[2], env)?)
                 }
                 Sym(a0sym) if a0sym == "let*" => {
+                    check_arity("let*", l.len() - 1, 2, Some(2))?;
                     let let_env = &env_new(Some(env.clone()));
                     let (a1, a2) = (&l[1], &l[2]);
                     match a1 {
@@ -103,13 +123,23 @@ fn rep(str: &str, env: &Env) -> Result<String, MalErr> {
     Ok(print(&exp))
 }
 
-fn int_op(op: fn(i64, i64) -> i64, a: MalArgs) -> MalRet {
-    match (a[0].clone(), a[1].clone()) {
-        (Int(a0), Int(a1)) => Ok(Int(op(a0, a1))),
+fn int_op(op: fn(i64, i64) -> Option<i64>, a: MalArgs) -> MalRet {
+    match a[..] {
+        [Int(a0), Int(a1)] => match op(a0, a1) {
+            Some(r) => Ok(Int(r)),
+            None => error("integer overflow"),
+        },
         _ => error("invalid int_op args"),
     }
 }
 
+fn divide(a: MalArgs) -> MalRet {
+    match a[..] {
+        [Int(_), Int(0)] => error("division by zero"),
+        _ => int_op(i64::checked_div, a),
+    }
+}
+
 fn main() {
     // `()` can be used when no completer is required
     let mut rl = Editor::<(), rustyline::history::DefaultHistory>::new().unwrap();
@@ -118,10 +148,10 @@ fn main() {
     }
 
     let repl_env = env_new(None);
-    env_sets(&repl_env, "+", func(|a: MalArgs| int_op(|i, j| i + j, a)));
-    env_sets(&repl_env, "-", func(|a: MalArgs| int_op(|i, j| i - j, a)));
-    env_sets(&repl_env, "*", func(|a: MalArgs| int_op(|i, j| i * j, a)));
-    env_sets(&repl_env, "/", func(|a: MalArgs| int_op(|i, j| i / j, a)));
+    env_sets(&repl_env, "+", func(|a: MalArgs| int_op(i64::checked_add, a)));
+    env_sets(&repl_env, "-", func(|a: MalArgs| int_op(i64::checked_sub, a)));
+    env_sets(&repl_env, "*", func(|a: MalArgs| int_op(i64::checked_mul, a)));
+    env_sets(&repl_env, "/", func(divide));
 
     loop {
         let readline = rl.readline("user> ");
@@ -138,6 +168,11 @@ fn main() {
             }
             Err(ReadlineError::Interrupted) => continue,
             Err(ReadlineError::Eof) => break,
+            // A line that is not valid UTF-8 is dropped, not the session.
+            Err(ReadlineError::Io(err)) if err.kind() == io::ErrorKind::InvalidData => {
+                println!("Error: {}", err);
+                continue;
+            }
             Err(err) => {
                 println!("Error: {:?}", err);
                 break;
@@ -1,11 +1,12 @@
+use std::io;
 use std::rc::Rc;
 //use std::collections::HashMap;
-use fnv::FnvHashMap;
 use itertools::Itertools;
 
 #[macro_use]
 extern crate lazy_static;
 extern crate fnv;
+extern crate indexmap;
 extern crate itertools;
 extern crate regex;
 
@@ -15,12 +16,15 @@ use rustyline::Editor;
 
 #[macro_use]
 mod types;
-use crate::types::MalVal::{Bool, Func, Hash, List, MalFunc, Nil, Sym, Vector};
-use crate::types::{error, format_error, MalArgs, MalErr, MalRet, MalVal};
+use crate::types::MalVal::{Func, Hash, List, MalFunc, Nil, Set, Sym, Vector};
+use crate::types::{
+    check_arity, check_key, error, format_error, hash_set, MalArgs, MalErr, MalMap, MalRet, MalVal,
+};
+#[allow(dead_code)]
 mod env;
 mod printer;
 mod reader;
-use crate::env::{env_bind, env_get, env_new, env_set, env_sets, Env};
+use crate::env::{env_bind, env_get, env_new, env_not_found, env_set, env_sets, Env};
 #[macro_use]
 mod core;
 
@@ -40,13 +44,13 @@ fn eval(orig_ast: &MalVal, orig_env: &Env) -> MalRet {
 
     'tco: loop {
         match env_get(env, "DEBUG-EVAL") {
-            None | Some(Bool(false)) | Some(Nil) => (),
-            _ => println!("EVAL: {}", print(ast)),
+            Some(v) if v.truthy() => println!("EVAL: {}", print(ast)),
+            _ => (),
         }
         match ast {
         Sym(s) => match env_get(env, s) {
             Some(r) => return Ok(r),
-            None => return error(&format!("'{}' not found", s)),
+            None => return env_not_found(env, s),
         }
         Vector(v, _) => {
             let mut lst: MalArgs = vec![];
@@ -56,12 +60,23 @@ fn eval(orig_ast: &MalVal, orig_env: &Env) -> MalRet {
             return Ok(vector!(lst));
         }
         Hash(hm, _) => {
-            let mut new_hm: FnvHashMap<String, MalVal> = FnvHashMap::default();
+            let mut new_hm = MalMap::default();
+            // Keys are evaluated as well, and ones that come out equal
+            // collapse into one entry with the later value.
             for (k, v) in hm.iter() {
-                new_hm.insert(k.to_string(), eval(v, env)?);
+                let k = eval(k, env)?;
+                check_key(&k)?;
+                new_hm.insert(k, eval(v, env)?);
             }
             return Ok(Hash(Rc::new(new_hm), Rc::new(Nil)));
         }
+        Set(s, _) => {
+            let mut items: MalArgs = vec![];
+            for a in s.iter() {
+                items.push(eval(a, env)?);
+            }
+            return hash_set(items);
+        }
         List(l, _) => {
                 if l.is_empty() {
                     return Ok(ast.clone());
@@ -69,9 +84,11 @@ fn eval(orig_ast: &MalVal, orig_env: &Env) -> MalRet {
                 let a0 = &l[0];
                 match a0 {
                     Sym(a0sym) if a0sym == "def!" => {
+                        check_arity("def!", l.len() - 1, 2, Some(2))?;
                         return env_set(env, &l[1], eval(&l[2], env)?);
                     }
                     Sym(a0sym) if a0sym == "let*" => {
+                        check_arity("let*", l.len() - 1, 2, Some(2))?;
                         live_env = env_new(Some(env.clone()));
                         env = &live_env;
                         let (a1, a2) = (&l[1], &l[2]);
@@ -94,19 +111,20 @@ fn eval(orig_ast: &MalVal, orig_env: &Env) -> MalRet {
                         for i in 1..l.len() - 1 {
                             let _ = eval(&l[i], env)?;
                         }
-                        live_ast = l.last().unwrap_or(&Nil).clone();
+                        live_ast = l[1..].last().unwrap_or(&Nil).clone();
                         ast = &live_ast;
                         continue 'tco;
                     }
                     Sym(a0sym) if a0sym == "if" => {
+                        check_arity("if", l.len() - 1, 2, Some(3))?;
                         let cond = eval(&l[1], env)?;
                         match cond {
-                            Bool(false) | Nil if l.len() >= 4 => {
+                            _ if !cond.truthy() && l.len() >= 4 => {
                                 live_ast = l[3].clone();
                                 ast = &live_ast;
                                 continue 'tco;
                             }
-                            Bool(false) | Nil => return Ok(Nil),
+                            _ if !cond.truthy() => return Ok(Nil),
                             _ if l.len() >= 3 => {
                                 live_ast = l[2].clone();
                                 ast = &live_ast;
@@ -116,9 +134,10 @@ fn eval(orig_ast: &MalVal, orig_env: &Env) -> MalRet {
                         }
                     }
                     Sym(a0sym) if a0sym == "fn*" => {
+                        check_arity("fn*", l.len() - 1, 2, Some(2))?;
                         let (a1, a2) = (l[1].clone(), l[2].clone());
                         return Ok(MalFunc {
-                            eval,
+                            eval: |ast, env, _| eval(ast, env),
                             ast: Rc::new(a2),
                             env: env.clone(),
                             params: Rc::new(a1),
@@ -214,6 +233,11 @@ fn main() {
             }
             Err(ReadlineError::Interrupted) => continue,
             Err(ReadlineError::Eof) => break,
+            // A line that is not valid UTF-8 is dropped, not the session.
+            Err(ReadlineError::Io(err)) if err.kind() == io::ErrorKind::InvalidData => {
+                println!("Error: {}", err);
+                continue;
+            }
             Err(err) => {
                 println!("Error: {:?}", err);
                 break;
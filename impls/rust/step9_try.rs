@@ -1,11 +1,12 @@
+use std::io;
 use std::rc::Rc;
 //use std::collections::HashMap;
-use fnv::FnvHashMap;
 use itertools::Itertools;
 
 #[macro_use]
 extern crate lazy_static;
 extern crate fnv;
+extern crate indexmap;
 extern crate itertools;
 extern crate regex;
 
@@ -15,13 +16,14 @@ use rustyline::Editor;
 
 #[macro_use]
 mod types;
-use crate::types::MalErr::{ErrMalVal, ErrString};
-use crate::types::MalVal::{Bool, Func, Hash, List, MalFunc, Nil, Str, Sym, Vector};
-use crate::types::{error, format_error, MalArgs, MalErr, MalRet, MalVal};
+use crate::types::MalVal::{Func, Hash, List, MalFunc, Nil, Set, Str, Sym, Vector};
+use crate::types::{
+    check_arity, check_key, error, format_error, hash_set, MalArgs, MalErr, MalMap, MalRet, MalVal,
+};
 mod env;
 mod printer;
 mod reader;
-use crate::env::{env_bind, env_find_repl, env_get, env_new, env_set, env_sets, Env};
+use crate::env::{env_bind, env_find_repl, env_get, env_new, env_not_found, env_set, env_sets, Env};
 #[macro_use]
 mod core;
 
@@ -63,11 +65,31 @@ fn quasiquote(ast: &MalVal) -> MalVal {
             qq_iter(v)
         },
         Vector(v, _) => list![Sym("vec".to_string()), qq_iter(v)],
-        Hash(_, _) | Sym(_)=> list![Sym("quote".to_string()), ast.clone()],
+        Hash(_, _) | Set(_, _) | Sym(_)=> list![Sym("quote".to_string()), ast.clone()],
         _ => ast.clone(),
     }
 }
 
+fn is_macro_call(ast: &MalVal, env: &Env) -> Option<(MalVal, MalArgs)> {
+    match ast {
+        List(v, _) => match v.first() {
+            Some(Sym(ref s)) => match env_get(env, s) {
+                Some(f @ MalFunc { is_macro: true, .. }) => Some((f, v[1..].to_vec())),
+                _ => None,
+            },
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn macroexpand(mut ast: MalVal, env: &Env) -> MalRet {
+    while let Some((mf, args)) = is_macro_call(&ast, env) {
+        ast = mf.apply(args)?;
+    }
+    Ok(ast)
+}
+
 fn eval(orig_ast: &MalVal, orig_env: &Env) -> MalRet {
     let mut ast = orig_ast;
     let mut env = orig_env;
@@ -78,13 +100,13 @@ fn eval(orig_ast: &MalVal, orig_env: &Env) -> MalRet {
 
     'tco: loop {
         match env_get(env, "DEBUG-EVAL") {
-            None | Some(Bool(false)) | Some(Nil) => (),
-            _ => println!("EVAL: {}", print(ast)),
+            Some(v) if v.truthy() => println!("EVAL: {}", print(ast)),
+            _ => (),
         }
         match ast {
         Sym(s) => match env_get(env, s) {
             Some(r) => return Ok(r),
-            None => return error(&format!("'{}' not found", s)),
+            None => return env_not_found(env, s),
         }
         Vector(v, _) => {
             let mut lst: MalArgs = vec![];
@@ -94,12 +116,23 @@ fn eval(orig_ast: &MalVal, orig_env: &Env) -> MalRet {
             return Ok(vector!(lst));
         }
         Hash(hm, _) => {
-            let mut new_hm: FnvHashMap<String, MalVal> = FnvHashMap::default();
+            let mut new_hm = MalMap::default();
+            // Keys are evaluated as well, and ones that come out equal
+            // collapse into one entry with the later value.
             for (k, v) in hm.iter() {
-                new_hm.insert(k.to_string(), eval(v, env)?);
+                let k = eval(k, env)?;
+                check_key(&k)?;
+                new_hm.insert(k, eval(v, env)?);
             }
             return Ok(Hash(Rc::new(new_hm), Rc::new(Nil)));
         }
+        Set(s, _) => {
+            let mut items: MalArgs = vec![];
+            for a in s.iter() {
+                items.push(eval(a, env)?);
+            }
+            return hash_set(items);
+        }
         List(l, _) => {
                 if l.is_empty() {
                     return Ok(ast.clone());
@@ -107,9 +140,11 @@ fn eval(orig_ast: &MalVal, orig_env: &Env) -> MalRet {
                 let a0 = &l[0];
                 match a0 {
                     Sym(a0sym) if a0sym == "def!" => {
+                        check_arity("def!", l.len() - 1, 2, Some(2))?;
                         return env_set(env, &l[1], eval(&l[2], env)?);
                     }
                     Sym(a0sym) if a0sym == "let*" => {
+                        check_arity("let*", l.len() - 1, 2, Some(2))?;
                         live_env = env_new(Some(env.clone()));
                         env = &live_env;
                         let (a1, a2) = (&l[1], &l[2]);
@@ -128,13 +163,18 @@ fn eval(orig_ast: &MalVal, orig_env: &Env) -> MalRet {
                         ast = &live_ast;
                         continue 'tco;
                     }
-                    Sym(a0sym) if a0sym == "quote" => return Ok(l[1].clone()),
+                    Sym(a0sym) if a0sym == "quote" => {
+                        check_arity("quote", l.len() - 1, 1, Some(1))?;
+                        return Ok(l[1].clone());
+                    }
                     Sym(a0sym) if a0sym == "quasiquote" => {
+                        check_arity("quasiquote", l.len() - 1, 1, Some(1))?;
                         live_ast = quasiquote(&l[1]);
                         ast = &live_ast;
                         continue 'tco;
                     }
                     Sym(a0sym) if a0sym == "defmacro!" => {
+                        check_arity("defmacro!", l.len() - 1, 2, Some(2))?;
                         let (a1, a2) = (&l[1], &l[2]);
                         let r = eval(a2, env)?;
                         match r {
@@ -159,7 +199,19 @@ fn eval(orig_ast: &MalVal, orig_env: &Env) -> MalRet {
                             _ => return error("set_macro on non-function"),
                         }
                     }
+                    Sym(a0sym) if a0sym == "macroexpand-1" => {
+                        check_arity("macroexpand-1", l.len() - 1, 1, Some(1))?;
+                        return match is_macro_call(&l[1], env) {
+                            Some((mf, args)) => mf.apply(args),
+                            None => Ok(l[1].clone()),
+                        };
+                    }
+                    Sym(a0sym) if a0sym == "macroexpand" => {
+                        check_arity("macroexpand", l.len() - 1, 1, Some(1))?;
+                        return macroexpand(l[1].clone(), env);
+                    }
                     Sym(a0sym) if a0sym == "try*" => {
+                        check_arity("try*", l.len() - 1, 1, Some(2))?;
                         if l.len() < 3 {
                             live_ast = l[1].clone();
                             ast = &live_ast;
@@ -167,12 +219,10 @@ fn eval(orig_ast: &MalVal, orig_env: &Env) -> MalRet {
                         }
                         match eval(&l[1], env) {
                         Err(e) => {
-                            let exc = match e {
-                                ErrMalVal(mv) => mv.clone(),
-                                ErrString(s) => Str(s.to_string()),
-                            };
+                            let exc = MalVal::from(e);
                             match &l[2] {
                                 List(c, _) => {
+                                    check_arity("catch*", c.len().saturating_sub(1), 2, Some(2))?;
                                     live_env = env_new(Some(env.clone()));
                                     env = &live_env;
                                     env_set(env, &c[1], exc)?;
@@ -190,19 +240,20 @@ fn eval(orig_ast: &MalVal, orig_env: &Env) -> MalRet {
                         for i in 1..l.len() - 1 {
                             let _ = eval(&l[i], env)?;
                         }
-                        live_ast = l.last().unwrap_or(&Nil).clone();
+                        live_ast = l[1..].last().unwrap_or(&Nil).clone();
                         ast = &live_ast;
                         continue 'tco;
                     }
                     Sym(a0sym) if a0sym == "if" => {
+                        check_arity("if", l.len() - 1, 2, Some(3))?;
                         let cond = eval(&l[1], env)?;
                         match cond {
-                            Bool(false) | Nil if l.len() >= 4 => {
+                            _ if !cond.truthy() && l.len() >= 4 => {
                                 live_ast = l[3].clone();
                                 ast = &live_ast;
                                 continue 'tco;
                             }
-                            Bool(false) | Nil => return Ok(Nil),
+                            _ if !cond.truthy() => return Ok(Nil),
                             _ if l.len() >= 3 => {
                                 live_ast = l[2].clone();
                                 ast = &live_ast;
@@ -212,9 +263,10 @@ fn eval(orig_ast: &MalVal, orig_env: &Env) -> MalRet {
                         }
                     }
                     Sym(a0sym) if a0sym == "fn*" => {
+                        check_arity("fn*", l.len() - 1, 2, Some(2))?;
                         let (a1, a2) = (l[1].clone(), l[2].clone());
                         return Ok(MalFunc {
-                            eval,
+                            eval: |ast, env, _| eval(ast, env),
                             ast: Rc::new(a2),
                             env: env.clone(),
                             params: Rc::new(a1),
@@ -223,6 +275,7 @@ fn eval(orig_ast: &MalVal, orig_env: &Env) -> MalRet {
                         })
                     }
                     Sym(a0sym) if a0sym == "eval" => {
+                        check_arity("eval", l.len() - 1, 1, Some(1))?;
                         //  Hard to implement without global variables.
                         //  Normal argument evaluation.
                         live_ast = eval(&l[1], env)?;
@@ -341,6 +394,11 @@ fn main() {
             }
             Err(ReadlineError::Interrupted) => continue,
             Err(ReadlineError::Eof) => break,
+            // A line that is not valid UTF-8 is dropped, not the session.
+            Err(ReadlineError::Io(err)) if err.kind() == io::ErrorKind::InvalidData => {
+                println!("Error: {}", err);
+                continue;
+            }
             Err(err) => {
                 println!("Error: {:?}", err);
                 break;
@@ -1,13 +1,20 @@
 #![allow(non_snake_case)]
 
+use std::any::Any;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
 use std::rc::Rc;
 //use std::collections::HashMap;
-use fnv::FnvHashMap;
 use itertools::Itertools;
 
 #[macro_use]
 extern crate lazy_static;
 extern crate fnv;
+extern crate indexmap;
 extern crate itertools;
 extern crate regex;
 
@@ -17,24 +24,32 @@ use rustyline::Editor;
 
 #[macro_use]
 mod types;
-use crate::types::MalErr::{ErrMalVal, ErrString};
-use crate::types::MalVal::{Bool, Func, Hash, List, MalFunc, Nil, Str, Sym, Vector};
-use crate::types::{error, format_error, MalArgs, MalErr, MalRet, MalVal};
+use crate::types::MalErr::{ArityError, ErrString};
+use crate::types::MalVal::{Bool, Func, Hash, Int, List, MalFunc, Nil, Set, Str, Sym, Vector};
+use crate::types::{
+    check_arity, check_key, error, fn_clause, format_error, hash_set, MalArgs, MalErr, MalMap,
+    MalRet, MalVal,
+};
 mod env;
 mod printer;
 mod reader;
-use crate::env::{env_bind, env_find_repl, env_get, env_new, env_set, env_sets, Env};
+use crate::env::{
+    env_bind, env_find_repl, env_get, env_new, env_not_found, env_set, env_set_native, env_sets, Env,
+};
 #[macro_use]
 mod core;
 
 // read
+// Core functions written in mal itself, evaluated at startup.
+const PRELUDE: &str = include_str!("prelude.mal");
+
 fn read(str: &str) -> MalRet {
     reader::read_str(str)
 }
 
 // eval
 
-fn qq_iter(elts: &MalArgs) -> MalVal {
+fn qq_iter(elts: &MalArgs, gensyms: &mut HashMap<String, MalVal>) -> MalVal {
     let mut acc = list![];
     for elt in elts.iter().rev() {
         if let List(v, _) = elt {
@@ -47,12 +62,14 @@ fn qq_iter(elts: &MalArgs) -> MalVal {
                 }
             }
         }
-        acc = list![Sym("cons".to_string()), quasiquote(elt), acc];
+        acc = list![Sym("cons".to_string()), quasiquote(elt, gensyms), acc];
     }
     acc
 }
 
-fn quasiquote(ast: &MalVal) -> MalVal {
+// A symbol ending in # becomes a gensym, the same one for every use of
+// that name within one quasiquote.
+fn quasiquote(ast: &MalVal, gensyms: &mut HashMap<String, MalVal>) -> MalVal {
     match ast {
         List(v, _) => {
             if v.len() == 2 {
@@ -62,15 +79,129 @@ fn quasiquote(ast: &MalVal) -> MalVal {
                     }
                 }
             }
-            qq_iter(v)
+            qq_iter(v, gensyms)
         },
-        Vector(v, _) => list![Sym("vec".to_string()), qq_iter(v)],
-        Hash(_, _) | Sym(_)=> list![Sym("quote".to_string()), ast.clone()],
+        Vector(v, _) => list![Sym("vec".to_string()), qq_iter(v, gensyms)],
+        Sym(s) if s.len() > 1 && s.ends_with('#') => {
+            let g = gensyms
+                .entry(s.to_string())
+                .or_insert_with(|| core::gensym(&format!("{}__", &s[..s.len() - 1])));
+            list![Sym("quote".to_string()), g.clone()]
+        }
+        Hash(_, _) | Set(_, _) | Sym(_)=> list![Sym("quote".to_string()), ast.clone()],
         _ => ast.clone(),
     }
 }
 
-fn eval(orig_ast: &MalVal, orig_env: &Env) -> MalRet {
+fn is_macro_call(ast: &MalVal, env: &Env) -> Option<(MalVal, MalArgs)> {
+    match ast {
+        List(v, _) => match v.first() {
+            Some(Sym(ref s)) => match env_get(env, s) {
+                Some(f @ MalFunc { is_macro: true, .. }) => Some((f, v[1..].to_vec())),
+                _ => None,
+            },
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn macroexpand(mut ast: MalVal, env: &Env) -> MalRet {
+    while let Some((mf, args)) = is_macro_call(&ast, env) {
+        ast = mf.apply(args)?;
+    }
+    Ok(ast)
+}
+
+// Expand macro calls at every level of ast, leaving quoted forms alone.
+fn macroexpand_all(ast: &MalVal, env: &Env) -> MalRet {
+    let ast = macroexpand(ast.clone(), env)?;
+    match ast {
+        List(ref l, _) => match l.first() {
+            Some(Sym(ref s)) if s == "quote" || s == "quasiquote" => Ok(ast.clone()),
+            _ => Ok(list!(l
+                .iter()
+                .map(|a| macroexpand_all(a, env))
+                .collect::<Result<MalArgs, MalErr>>()?)),
+        },
+        Vector(ref v, _) => Ok(vector!(v
+            .iter()
+            .map(|a| macroexpand_all(a, env))
+            .collect::<Result<MalArgs, MalErr>>()?)),
+        Hash(ref hm, _) => {
+            let mut new_hm = MalMap::default();
+            for (k, v) in hm.iter() {
+                new_hm.insert(k.clone(), macroexpand_all(v, env)?);
+            }
+            Ok(Hash(Rc::new(new_hm), Rc::new(Nil)))
+        }
+        _ => Ok(ast),
+    }
+}
+
+thread_local! {
+    // Forms evaluated since start or the last eval-count-reset!,
+    // tail calls included.
+    static EVAL_COUNT: Cell<i64> = const { Cell::new(0) };
+    // Nested eval calls currently running.
+    static EVAL_DEPTH: Cell<usize> = const { Cell::new(0) };
+    // Deeper non-tail recursion is reported as an error rather than
+    // overflowing the stack. Set from MAL_MAX_EVAL_DEPTH at startup.
+    static MAX_EVAL_DEPTH: Cell<usize> = const { Cell::new(DEFAULT_MAX_EVAL_DEPTH) };
+}
+
+// As many nested evals as STACK_SIZE holds, with room to spare. The
+// deepest path measured, recursion through reduce, overflows at about
+// 118,000 evals in a release build but only 11,000 in a debug one,
+// whose frames are far larger. The limit counts every nested eval, so
+// a non-tail recursion uses about two of it per call.
+//
+// Only this step has the limit. Steps 5 to 9 still abort with a native
+// stack overflow on runaway recursion.
+const DEFAULT_MAX_EVAL_DEPTH: usize = if cfg!(debug_assertions) { 10_000 } else { 100_000 };
+// Enough stack for DEFAULT_MAX_EVAL_DEPTH evals; a higher limit gets
+// proportionally more.
+const STACK_SIZE: usize = 256 * 1024 * 1024;
+
+struct DepthGuard;
+
+impl DepthGuard {
+    fn enter() -> Result<DepthGuard, MalErr> {
+        EVAL_DEPTH.with(|d| {
+            if d.get() >= MAX_EVAL_DEPTH.with(|m| m.get()) {
+                return Err(ErrString("recursion limit exceeded".to_string()));
+            }
+            d.set(d.get() + 1);
+            Ok(DepthGuard)
+        })
+    }
+}
+
+impl Drop for DepthGuard {
+    fn drop(&mut self) {
+        EVAL_DEPTH.with(|d| d.set(d.get() - 1));
+    }
+}
+
+// The params, body and outer env of the innermost loop or function
+// whose body is in tail position, for recur to jump back to.
+type RecurTarget = (MalVal, MalVal, Env);
+
+fn eval(ast: &MalVal, env: &Env) -> MalRet {
+    eval_in(ast, env, None)
+}
+
+// A function body, where recur calls the function again, binding its
+// params the way a call does.
+fn eval_fn_body(body: &MalVal, env: &Env, params: &MalVal) -> MalRet {
+    match &env.outer {
+        Some(outer) => eval_in(body, env, Some((params.clone(), body.clone(), outer.clone()))),
+        None => eval(body, env),
+    }
+}
+
+fn eval_in(orig_ast: &MalVal, orig_env: &Env, mut recur_target: Option<RecurTarget>) -> MalRet {
+    let _depth = DepthGuard::enter()?;
     let mut ast = orig_ast;
     let mut env = orig_env;
     // These variables ensure a sufficient lifetime for the data
@@ -79,14 +210,15 @@ fn eval(orig_ast: &MalVal, orig_env: &Env) -> MalRet {
     let mut live_env;
 
     'tco: loop {
+        EVAL_COUNT.with(|c| c.set(c.get() + 1));
         match env_get(env, "DEBUG-EVAL") {
-            None | Some(Bool(false)) | Some(Nil) => (),
-            _ => println!("EVAL: {}", print(ast)),
+            Some(v) if v.truthy() => println!("EVAL: {}", print(ast)),
+            _ => (),
         }
         match ast {
         Sym(s) => match env_get(env, s) {
             Some(r) => return Ok(r),
-            None => return error(&format!("'{}' not found", s)),
+            None => return env_not_found(env, s),
         }
         Vector(v, _) => {
             let mut lst: MalArgs = vec![];
@@ -96,12 +228,23 @@ fn eval(orig_ast: &MalVal, orig_env: &Env) -> MalRet {
             return Ok(vector!(lst));
         }
         Hash(hm, _) => {
-            let mut new_hm: FnvHashMap<String, MalVal> = FnvHashMap::default();
+            let mut new_hm = MalMap::default();
+            // Keys are evaluated as well, and ones that come out equal
+            // collapse into one entry with the later value.
             for (k, v) in hm.iter() {
-                new_hm.insert(k.to_string(), eval(v, env)?);
+                let k = eval(k, env)?;
+                check_key(&k)?;
+                new_hm.insert(k, eval(v, env)?);
             }
             return Ok(Hash(Rc::new(new_hm), Rc::new(Nil)));
         }
+        Set(s, _) => {
+            let mut items: MalArgs = vec![];
+            for a in s.iter() {
+                items.push(eval(a, env)?);
+            }
+            return hash_set(items);
+        }
         List(l, _) => {
                 if l.is_empty() {
                     return Ok(ast.clone());
@@ -109,14 +252,18 @@ fn eval(orig_ast: &MalVal, orig_env: &Env) -> MalRet {
                 let a0 = &l[0];
                 match a0 {
                     Sym(a0sym) if a0sym == "def!" => {
+                        check_arity("def!", l.len() - 1, 2, Some(2))?;
                         return env_set(env, &l[1], eval(&l[2], env)?);
                     }
                     Sym(a0sym) if a0sym == "let*" => {
+                        check_arity("let*", l.len() - 1, 2, Some(2))?;
                         live_env = env_new(Some(env.clone()));
                         env = &live_env;
                         let (a1, a2) = (&l[1], &l[2]);
                         match a1 {
                             List(binds, _) | Vector(binds, _) => {
+                                // Bindings are sequential: each one sees
+                                // the ones before it, and may shadow them.
                                 for (b, e) in binds.iter().tuples() {
                                     let val = eval(e, env)?;
                                     env_set(env, b, val)?;
@@ -130,13 +277,60 @@ fn eval(orig_ast: &MalVal, orig_env: &Env) -> MalRet {
                         ast = &live_ast;
                         continue 'tco;
                     }
-                    Sym(a0sym) if a0sym == "quote" => return Ok(l[1].clone()),
+                    Sym(a0sym) if a0sym == "loop" => {
+                        check_arity("loop", l.len() - 1, 2, Some(2))?;
+                        let outer = env.clone();
+                        live_env = env_new(Some(outer.clone()));
+                        env = &live_env;
+                        let (a1, a2) = (&l[1], &l[2]);
+                        let mut syms = vec![];
+                        match a1 {
+                            List(binds, _) | Vector(binds, _) => {
+                                for (b, e) in binds.iter().tuples() {
+                                    let val = eval(e, env)?;
+                                    env_set(env, b, val)?;
+                                    syms.push(b.clone());
+                                }
+                            }
+                            _ => {
+                                return error("loop with non-List bindings");
+                            }
+                        };
+                        recur_target = Some((list!(syms), a2.clone(), outer));
+                        live_ast = a2.clone();
+                        ast = &live_ast;
+                        continue 'tco;
+                    }
+                    Sym(a0sym) if a0sym == "recur" => {
+                        let (params, body, outer) = match recur_target {
+                            Some(ref t) => t.clone(),
+                            None => return error("recur outside of tail position"),
+                        };
+                        let mut vals: MalArgs = vec![];
+                        for i in 1..l.len() {
+                            vals.push(eval(&l[i], env)?);
+                        }
+                        live_env = match env_bind(Some(outer), &params, vals) {
+                            Err(ArityError(m)) => return Err(ArityError(format!("recur: {}", m))),
+                            res => res?,
+                        };
+                        env = &live_env;
+                        live_ast = body;
+                        ast = &live_ast;
+                        continue 'tco;
+                    }
+                    Sym(a0sym) if a0sym == "quote" => {
+                        check_arity("quote", l.len() - 1, 1, Some(1))?;
+                        return Ok(l[1].clone());
+                    }
                     Sym(a0sym) if a0sym == "quasiquote" => {
-                        live_ast = quasiquote(&l[1]);
+                        check_arity("quasiquote", l.len() - 1, 1, Some(1))?;
+                        live_ast = quasiquote(&l[1], &mut HashMap::new());
                         ast = &live_ast;
                         continue 'tco;
                     }
                     Sym(a0sym) if a0sym == "defmacro!" => {
+                        check_arity("defmacro!", l.len() - 1, 2, Some(2))?;
                         let (a1, a2) = (&l[1], &l[2]);
                         let r = eval(a2, env)?;
                         match r {
@@ -161,7 +355,49 @@ fn eval(orig_ast: &MalVal, orig_env: &Env) -> MalRet {
                             _ => return error("set_macro on non-function"),
                         }
                     }
+                    Sym(a0sym) if a0sym == "macroexpand-1" => {
+                        check_arity("macroexpand-1", l.len() - 1, 1, Some(1))?;
+                        return match is_macro_call(&l[1], env) {
+                            Some((mf, args)) => mf.apply(args),
+                            None => Ok(l[1].clone()),
+                        };
+                    }
+                    Sym(a0sym) if a0sym == "macroexpand" => {
+                        check_arity("macroexpand", l.len() - 1, 1, Some(1))?;
+                        return macroexpand(l[1].clone(), env);
+                    }
+                    Sym(a0sym) if a0sym == "macroexpand-all" => {
+                        check_arity("macroexpand-all", l.len() - 1, 1, Some(1))?;
+                        return macroexpand_all(&l[1], env);
+                    }
                     Sym(a0sym) if a0sym == "try*" => {
+                        check_arity("try*", l.len() - 1, 1, Some(3))?;
+                        // A trailing (finally* ...) runs once the body and
+                        // any catch* are done, whether or not they threw,
+                        // so neither of them is in tail position.
+                        let finally = match l.last() {
+                            Some(List(f, _)) if l.len() > 2 => match f.first() {
+                                Some(Sym(s)) if s == "finally*" => Some(f),
+                                _ => None,
+                            },
+                            _ => None,
+                        };
+                        if let Some(f) = finally {
+                            let res = match (eval(&l[1], env), &l[2..l.len() - 1]) {
+                                (Err(e), [List(c, _)]) => {
+                                    check_arity("catch*", c.len().saturating_sub(1), 2, Some(2))?;
+                                    let exc = MalVal::from(e);
+                                    let catch_env = env_new(Some(env.clone()));
+                                    env_set(&catch_env, &c[1], exc)?;
+                                    eval(&c[2], &catch_env)
+                                }
+                                (res, _) => res,
+                            };
+                            for form in f[1..].iter() {
+                                eval(form, env)?;
+                            }
+                            return res;
+                        }
                         if l.len() < 3 {
                             live_ast = l[1].clone();
                             ast = &live_ast;
@@ -169,12 +405,10 @@ fn eval(orig_ast: &MalVal, orig_env: &Env) -> MalRet {
                         }
                         match eval(&l[1], env) {
                         Err(e) => {
-                            let exc = match e {
-                                ErrMalVal(mv) => mv.clone(),
-                                ErrString(s) => Str(s.to_string()),
-                            };
+                            let exc = MalVal::from(e);
                             match &l[2] {
                                 List(c, _) => {
+                                    check_arity("catch*", c.len().saturating_sub(1), 2, Some(2))?;
                                     live_env = env_new(Some(env.clone()));
                                     env = &live_env;
                                     env_set(env, &c[1], exc)?;
@@ -192,19 +426,38 @@ fn eval(orig_ast: &MalVal, orig_env: &Env) -> MalRet {
                         for i in 1..l.len() - 1 {
                             let _ = eval(&l[i], env)?;
                         }
-                        live_ast = l.last().unwrap_or(&Nil).clone();
+                        live_ast = l[1..].last().unwrap_or(&Nil).clone();
+                        ast = &live_ast;
+                        continue 'tco;
+                    }
+                    Sym(a0sym) if a0sym == "and" || a0sym == "or" => {
+                        // Stops at the first falsy (and) or truthy (or)
+                        // value and returns it; the last form is in tail
+                        // position.
+                        let is_and = a0sym == "and";
+                        if l.len() == 1 {
+                            return Ok(if is_and { Bool(true) } else { Nil });
+                        }
+                        for a in &l[1..l.len() - 1] {
+                            let v = eval(a, env)?;
+                            if v.truthy() != is_and {
+                                return Ok(v);
+                            }
+                        }
+                        live_ast = l[l.len() - 1].clone();
                         ast = &live_ast;
                         continue 'tco;
                     }
                     Sym(a0sym) if a0sym == "if" => {
+                        check_arity("if", l.len() - 1, 2, Some(3))?;
                         let cond = eval(&l[1], env)?;
                         match cond {
-                            Bool(false) | Nil if l.len() >= 4 => {
+                            _ if !cond.truthy() && l.len() >= 4 => {
                                 live_ast = l[3].clone();
                                 ast = &live_ast;
                                 continue 'tco;
                             }
-                            Bool(false) | Nil => return Ok(Nil),
+                            _ if !cond.truthy() => return Ok(Nil),
                             _ if l.len() >= 3 => {
                                 live_ast = l[2].clone();
                                 ast = &live_ast;
@@ -214,9 +467,26 @@ fn eval(orig_ast: &MalVal, orig_env: &Env) -> MalRet {
                         }
                     }
                     Sym(a0sym) if a0sym == "fn*" => {
-                        let (a1, a2) = (l[1].clone(), l[2].clone());
+                        check_arity("fn*", l.len() - 1, 1, None)?;
+                        // (fn* ((a) ...) ((a b) ...)) has a clause per arity.
+                        let multi = match &l[1] {
+                            List(c, _) => matches!(c.first(), Some(List(_, _)) | Some(Vector(_, _))),
+                            _ => false,
+                        };
+                        let (a1, a2) = if multi {
+                            for c in l[1..].iter() {
+                                match c {
+                                    List(c, _) if c.len() == 2 && matches!(c[0], List(_, _) | Vector(_, _)) => (),
+                                    _ => return error("fn*: expected (params body) clauses"),
+                                }
+                            }
+                            (Nil, list!(l[1..].to_vec()))
+                        } else {
+                            check_arity("fn*", l.len() - 1, 2, Some(2))?;
+                            (l[1].clone(), l[2].clone())
+                        };
                         return Ok(MalFunc {
-                            eval,
+                            eval: eval_fn_body,
                             ast: Rc::new(a2),
                             env: env.clone(),
                             params: Rc::new(a1),
@@ -224,13 +494,27 @@ fn eval(orig_ast: &MalVal, orig_env: &Env) -> MalRet {
                             meta: Rc::new(Nil),
                         })
                     }
+                    Sym(a0sym) if a0sym == "stack-depth" => {
+                        // Scopes between the current env and the REPL env.
+                        // Function envs chain to where the function was
+                        // defined, so this is lexical rather than call depth.
+                        let mut depth = 0;
+                        let mut e = env;
+                        while let Some(outer) = &e.outer {
+                            depth += 1;
+                            e = outer;
+                        }
+                        return Ok(Int(depth));
+                    }
                     Sym(a0sym) if a0sym == "eval" => {
+                        check_arity("eval", l.len() - 1, 1, Some(1))?;
                         //  Hard to implement without global variables.
                         //  Normal argument evaluation.
                         live_ast = eval(&l[1], env)?;
                         ast = &live_ast;
                         live_env = env_find_repl(env);
                         env = &live_env;
+                        recur_target = None;
                         continue 'tco;
                     }
                     _ => match eval(a0, env) {
@@ -257,9 +541,11 @@ fn eval(orig_ast: &MalVal, orig_env: &Env) -> MalRet {
                                     for i in 1..l.len() {
                                         args.push(eval(&l[i], env)?);
                                     }
-                                    live_env = env_bind(Some(menv.clone()), &mparams, args.to_vec())?;
+                                    let (params, body) = fn_clause(&mparams, &mast, args.len())?;
+                                    live_env = env_bind(Some(menv.clone()), &params, args)?;
                                     env = &live_env;
-                                    live_ast = (*mast).clone();
+                                    recur_target = Some((params, body.clone(), menv));
+                                    live_ast = body;
                                     ast = &live_ast;
                                     continue 'tco;
                                 }
@@ -280,10 +566,66 @@ fn print(ast: &MalVal) -> String {
     ast.pr_str(true)
 }
 
+// Evaluates every form on the line and prints the last result. Recent
+// results are kept in *1, *2 and *3, and the last error in *e.
 fn rep(str: &str, env: &Env) -> Result<String, MalErr> {
-    let ast = read(str)?;
-    let exp = eval(&ast, env)?;
-    Ok(print(&exp))
+    let mut exp = None;
+    for ast in reader::read_forms(str.as_bytes()) {
+        match ast.and_then(|ast| eval(&ast, env)) {
+            Ok(val) => {
+                for (older, newer) in [("*3", "*2"), ("*2", "*1")] {
+                    env_sets(env, older, env_get(env, newer).unwrap_or(Nil));
+                }
+                env_sets(env, "*1", val.clone());
+                exp = Some(val);
+            }
+            Err(e) => {
+                env_sets(env, "*e", MalVal::from(e.clone()));
+                return Err(e);
+            }
+        }
+    }
+    match exp {
+        Some(exp) => match env_get(env, "*print-readably*") {
+            Some(v) if !v.truthy() => Ok(exp.pr_str(false)),
+            _ => Ok(print(&exp)),
+        },
+        None => Err(ErrString("no input".to_string())),
+    }
+}
+
+fn rep_line(line: &str, env: &Env) {
+    match catch_panic(|| rep(line, env)) {
+        Ok(out) => println!("{}", out),
+        Err(e) => println!("Error: {}", format_error(e)),
+    }
+}
+
+// A panic in a builtin is reported as an error rather than ending the
+// session. main installs a quiet panic hook, so this is all that shows.
+fn catch_panic<T>(f: impl FnOnce() -> Result<T, MalErr>) -> Result<T, MalErr> {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(res) => res,
+        Err(p) => Err(ErrString(panic_message(p))),
+    }
+}
+
+fn panic_message(p: Box<dyn Any + Send>) -> String {
+    match p.downcast::<String>() {
+        Ok(s) => *s,
+        Err(p) => match p.downcast::<&str>() {
+            Ok(s) => s.to_string(),
+            Err(_) => "internal error".to_string(),
+        },
+    }
+}
+
+// Evaluates the forms of src in env, stopping at the first error.
+fn load_prelude(src: &str, env: &Env) -> Result<(), MalErr> {
+    for form in reader::read_forms(src.as_bytes()) {
+        eval(&form?, env)?;
+    }
+    Ok(())
 }
 
 fn re(str: &str, env: &Env) {
@@ -295,7 +637,37 @@ fn re(str: &str, env: &Env) {
     panic!("error during startup");
 }
 
+// The eval depth limit from MAL_MAX_EVAL_DEPTH, if that is set.
+fn max_eval_depth() -> Result<usize, String> {
+    match std::env::var("MAL_MAX_EVAL_DEPTH") {
+        Ok(s) => s
+            .parse()
+            .map_err(|_| format!("MAL_MAX_EVAL_DEPTH is not a non-negative integer: {}", s)),
+        Err(_) => Ok(DEFAULT_MAX_EVAL_DEPTH),
+    }
+}
+
 fn main() {
+    panic::set_hook(Box::new(|_| {}));
+    let max_depth = match max_eval_depth() {
+        Ok(n) => n,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+    };
+    // Run on a thread whose stack can hold max_depth evals.
+    let stack_size = STACK_SIZE.max(max_depth.saturating_mul(STACK_SIZE / DEFAULT_MAX_EVAL_DEPTH));
+    let repl = std::thread::Builder::new().stack_size(stack_size).spawn(move || {
+        MAX_EVAL_DEPTH.with(|m| m.set(max_depth));
+        repl()
+    });
+    if !matches!(repl.map(|r| r.join()), Ok(Ok(()))) {
+        std::process::exit(1);
+    }
+}
+
+fn repl() {
     let mut args = std::env::args();
     let arg1 = args.nth(1);
 
@@ -311,40 +683,78 @@ fn main() {
         env_sets(&repl_env, k, v);
     }
     env_sets(&repl_env, "*ARGV*", list!(args.map(Str).collect()));
+    env_set_native(&repl_env, "eval-count", |_| Ok(Int(EVAL_COUNT.with(|c| c.get()))));
+    env_set_native(&repl_env, "eval-count-reset!", |_| {
+        EVAL_COUNT.with(|c| c.set(0));
+        Ok(Nil)
+    });
 
     // core.mal: defined using the language itself
-    re("(def! *host-language* \"rust\")", &repl_env);
-    re("(def! not (fn* (a) (if a false true)))", &repl_env);
-    re(
-        "(def! load-file (fn* (f) (eval (read-string (str \"(do \" (slurp f) \"\nnil)\")))))",
-        &repl_env,
-    );
-    re("(defmacro! cond (fn* (& xs) (if (> (count xs) 0) (list 'if (first xs) (if (> (count xs) 1) (nth xs 1) (throw \"odd number of forms to cond\")) (cons 'cond (rest (rest xs)))))))",
-        &repl_env);
+    if let Err(e) = load_prelude(PRELUDE, &repl_env) {
+        eprintln!("Error in prelude.mal: {}", format_error(e));
+        std::process::exit(1);
+    }
 
     if let Some(f) = arg1 {
-        // Invoked with arguments
-        re(&format!("(load-file \"{}\")", f), &repl_env);
+        // Invoked with arguments: run the script a form at a time
+        // rather than reading it into memory whole.
+        let file = match File::open(&f) {
+            Ok(file) => file,
+            Err(e) => {
+                eprintln!("Error: {}: {}", f, e);
+                std::process::exit(1);
+            }
+        };
+        for form in reader::read_forms(BufReader::new(file)) {
+            if let Err(e) = catch_panic(|| form.and_then(|ast| eval(&ast, &repl_env))) {
+                println!("Error: {}", format_error(e));
+                std::process::exit(1);
+            }
+        }
         std::process::exit(0);
     }
 
     // main repl loop
+    for sym in ["*1", "*2", "*3", "*e"] {
+        env_sets(&repl_env, sym, Nil);
+    }
     re("(println (str \"Mal [\" *host-language* \"]\"))", &repl_env);
+    // Lines of a form that is not finished yet.
+    let mut pending = String::new();
     loop {
-        let readline = rl.readline("user> ");
+        let prompt = if pending.is_empty() { "user> " } else { "...> " };
+        let readline = rl.readline(prompt);
         match readline {
             Ok(line) => {
                 let _ = rl.add_history_entry(&line);
-                rl.save_history(".mal-history").unwrap();
-                if !line.is_empty() {
-                    match rep(&line, &repl_env) {
-                        Ok(out) => println!("{}", out),
-                        Err(e) => println!("Error: {}", format_error(e)),
-                    }
+                let _ = rl.save_history(".mal-history");
+                pending.push_str(&line);
+                if reader::is_incomplete(&pending) {
+                    pending.push('\n');
+                    continue;
+                }
+                let input = mem::take(&mut pending);
+                if !input.is_empty() {
+                    rep_line(&input, &repl_env);
+                }
+            }
+            // Ctrl-C abandons a form that is still being entered.
+            Err(ReadlineError::Interrupted) => {
+                pending.clear();
+                continue;
+            }
+            Err(ReadlineError::Eof) => {
+                if !pending.is_empty() {
+                    rep_line(&pending, &repl_env);
                 }
+                break;
+            }
+            // A line that is not valid UTF-8 is dropped, not the session.
+            Err(ReadlineError::Io(err)) if err.kind() == io::ErrorKind::InvalidData => {
+                println!("Error: {}", err);
+                pending.clear();
+                continue;
             }
-            Err(ReadlineError::Interrupted) => continue,
-            Err(ReadlineError::Eof) => break,
             Err(err) => {
                 println!("Error: {:?}", err);
                 break;
@@ -352,3 +762,56 @@ fn main() {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{core, env_new, env_set_native, env_sets, eval, load_prelude, read, PRELUDE};
+    use crate::types::type_error;
+    use crate::types::MalVal::Str;
+
+    fn core_env() -> super::Env {
+        let env = env_new(None);
+        for (k, v) in core::ns() {
+            env_sets(&env, k, v);
+        }
+        env
+    }
+
+    fn rep(str: &str, env: &super::Env) -> String {
+        eval(&read(str).unwrap(), env).unwrap().pr_str(true)
+    }
+
+    #[test]
+    fn host_registers_native_function() {
+        let env = core_env();
+        // As a host program embedding mal would add its own.
+        env_set_native(&env, "str/reverse", |a| match a.first() {
+            Some(Str(s)) if a.len() == 1 && !a[0].keyword_q() => Ok(Str(s.chars().rev().collect())),
+            _ => type_error("str/reverse: expected a string"),
+        });
+        assert_eq!(rep("(str/reverse \"stressed\")", &env), "\"desserts\"");
+        assert_eq!(rep("(fn? str/reverse)", &env), "true");
+        assert_eq!(rep("(map str/reverse [\"ab\" \"\"])", &env), "(\"ba\" \"\")");
+        match eval(&read("(str/reverse :kw)").unwrap(), &env) {
+            Err(e) => assert_eq!(e.to_string(), "str/reverse: expected a string"),
+            Ok(v) => panic!("returned {}", v.pr_str(true)),
+        }
+    }
+
+    #[test]
+    fn prelude_loads() {
+        let env = core_env();
+        load_prelude(PRELUDE, &env).unwrap();
+        assert_eq!(rep("*host-language*", &env), "\"rust2\"");
+        assert_eq!(rep("(inc (cond false 1 :else 2))", &env), "3");
+    }
+
+    #[test]
+    fn prelude_error_is_returned() {
+        let env = core_env();
+        let e = load_prelude("(def! a 1) (def! b (undefined-fn)) (def! c 3)", &env).unwrap_err();
+        assert_eq!(e.to_string(), "'undefined-fn' not found");
+        assert_eq!(rep("a", &env), "1");
+        assert!(eval(&read("c").unwrap(), &env).is_err());
+    }
+}
@@ -0,0 +1,87 @@
+// Runs stepA on the scripts in this directory, the way a pipeline or
+// a user at a shell would.
+
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+fn run_script(script: &str, stdin: &[u8]) -> Output {
+    run_script_with_env(script, stdin, &[])
+}
+
+fn run_script_with_env(script: &str, stdin: &[u8], vars: &[(&str, &str)]) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_stepA_mal"))
+        .arg(format!("{}/tests/{}", env!("CARGO_MANIFEST_DIR"), script))
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .envs(vars.iter().copied())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(stdin).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+fn stdout(out: &Output) -> String {
+    String::from_utf8_lossy(&out.stdout).trim_end().to_string()
+}
+
+#[test]
+fn slurp_stdin_reads_piped_input() {
+    let out = run_script("print_stdin.mal", b"one\ntwo\n\nthree");
+    assert!(out.status.success());
+    assert_eq!(stdout(&out), r#""one\ntwo\n\nthree""#);
+}
+
+#[test]
+fn slurp_stdin_reads_empty_input() {
+    let out = run_script("print_stdin.mal", b"");
+    assert!(out.status.success());
+    assert_eq!(stdout(&out), r#""""#);
+}
+
+#[test]
+fn slurp_stdin_reports_invalid_utf8() {
+    let out = run_script("print_stdin.mal", b"caf\xe9");
+    assert_eq!(out.status.code(), Some(1));
+    assert!(stdout(&out).starts_with("Error: "), "{}", stdout(&out));
+}
+
+#[test]
+fn asserts_pass() {
+    let out = run_script("asserts.mal", b"");
+    assert!(
+        out.status.success() && stdout(&out).ends_with("all assertions passed"),
+        "asserts.mal failed:\n{}",
+        stdout(&out)
+    );
+}
+
+#[test]
+fn failed_assert_stops_the_script() {
+    let out = run_script("failing-assert.mal", b"");
+    assert_eq!(out.status.code(), Some(1));
+    assert_eq!(stdout(&out), r#"Error: "assertion failed: (= 1 2)""#);
+}
+
+#[test]
+fn recursion_limit_has_a_default() {
+    let out = run_script("recursion-depth.mal", b"");
+    assert!(out.status.success());
+    assert_eq!(stdout(&out), "210\n500500");
+}
+
+#[test]
+fn recursion_limit_is_read_from_the_environment() {
+    let out = run_script_with_env("recursion-depth.mal", b"", &[("MAL_MAX_EVAL_DEPTH", "200")]);
+    assert!(out.status.success());
+    assert_eq!(stdout(&out), "210\n\"recursion limit exceeded\"");
+}
+
+#[test]
+fn invalid_recursion_limit_is_an_error() {
+    let out = run_script_with_env("recursion-depth.mal", b"", &[("MAL_MAX_EVAL_DEPTH", "deep")]);
+    assert_eq!(out.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(stderr.contains("MAL_MAX_EVAL_DEPTH is not a non-negative integer: deep"), "{}", stderr);
+}
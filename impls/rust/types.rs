@@ -1,27 +1,39 @@
 use std::cell::RefCell;
+use std::error::Error;
+use std::fmt;
+use std::hash::Hasher;
 use std::rc::Rc;
 //use std::collections::HashMap;
-use fnv::FnvHashMap;
+use fnv::{FnvBuildHasher, FnvHasher};
+use indexmap::{IndexMap, IndexSet};
 use itertools::Itertools;
+use regex::Regex;
 
 use crate::env::{env_bind, Env};
-use crate::types::MalErr::{ErrMalVal, ErrString};
-use crate::types::MalVal::{Atom, Bool, Func, Hash, Int, List, MalFunc, Nil, Str, Sym, Vector};
+use crate::types::MalErr::{ArityError, ErrString, ReaderError, Thrown, TypeError, UnknownSymbol};
+use crate::types::MalVal::{
+    Atom, Bool, Float, Func, Hash, Int, List, MalFunc, Nil, Pattern, Set, Str, Sym, Vector,
+};
 
 #[derive(Clone)]
 pub enum MalVal {
     Nil,
     Bool(bool),
     Int(i64),
-    //Float(f64),
+    Float(f64),
     Str(String),
     Sym(String),
     List(Rc<Vec<MalVal>>, Rc<MalVal>),
     Vector(Rc<Vec<MalVal>>, Rc<MalVal>),
-    Hash(Rc<FnvHashMap<String, MalVal>>, Rc<MalVal>),
-    Func(fn(MalArgs) -> MalRet, Rc<MalVal>),
+    Hash(Rc<MalMap>, Rc<MalVal>),
+    Set(Rc<MalSet>, Rc<MalVal>),
+    // A compiled regular expression, read from #"...".
+    Pattern(Rc<Regex>),
+    Func(Rc<dyn Fn(MalArgs) -> MalRet>, Rc<MalVal>),
     MalFunc {
-        eval: fn(ast: &MalVal, env: &Env) -> MalRet,
+        // Runs a body in the env its params are bound in. The params
+        // are passed too, for steps where recur can rebind them.
+        eval: fn(ast: &MalVal, env: &Env, params: &MalVal) -> MalRet,
         ast: Rc<MalVal>,
         env: Env,
         params: Rc<MalVal>,
@@ -31,12 +43,27 @@ pub enum MalVal {
     Atom(Rc<RefCell<MalVal>>),
 }
 
+// The kind of an error, so that a host program can tell them apart.
+// ErrString covers everything without a kind of its own.
+#[derive(Clone)]
 pub enum MalErr {
     ErrString(String),
-    ErrMalVal(MalVal),
+    ReaderError(String),
+    UnknownSymbol(String),
+    TypeError(String),
+    ArityError(String),
+    // A value raised by throw.
+    Thrown(MalVal),
 }
 
 pub type MalArgs = Vec<MalVal>;
+// Hash maps keep their keys in insertion order so that printing them
+// is deterministic. Updating a key leaves it where it was. Any value
+// but a function or an atom can be a key; see check_key.
+pub type MalMap = IndexMap<MalVal, MalVal, FnvBuildHasher>;
+// Sets keep insertion order too, and take the same elements maps take
+// as keys.
+pub type MalSet = IndexSet<MalVal, FnvBuildHasher>;
 pub type MalRet = Result<MalVal, MalErr>;
 
 // type utility macros
@@ -67,13 +94,77 @@ pub fn error(s: &str) -> MalRet {
     Err(ErrString(s.to_string()))
 }
 
+pub fn reader_error(s: &str) -> MalRet {
+    Err(ReaderError(s.to_string()))
+}
+
+pub fn type_error(s: &str) -> MalRet {
+    Err(TypeError(s.to_string()))
+}
+
+pub fn arity_error(s: &str) -> MalRet {
+    Err(ArityError(s.to_string()))
+}
+
+// An ArityError unless name, a builtin or special form, was given
+// between min and max (if any) arguments.
+pub fn check_arity(name: &str, n: usize, min: usize, max: Option<usize>) -> Result<(), MalErr> {
+    if n >= min && max.is_none_or(|max| n <= max) {
+        return Ok(());
+    }
+    let expected = match max {
+        Some(max) if max == min => min.to_string(),
+        Some(max) if max == min + 1 => format!("{} or {}", min, max),
+        Some(max) => format!("{} to {}", min, max),
+        None => format!("at least {}", min),
+    };
+    Err(ArityError(format!(
+        "{}: wrong number of arguments: expected {}, got {}",
+        name, expected, n
+    )))
+}
+
 pub fn format_error(e: MalErr) -> String {
-    match e {
-        ErrString(s) => s,
-        ErrMalVal(mv) => mv.pr_str(true),
+    e.to_string()
+}
+
+// The value try*/catch* binds: what was thrown, or the message.
+impl From<MalErr> for MalVal {
+    fn from(e: MalErr) -> MalVal {
+        match e {
+            Thrown(mv) => mv,
+            e => Str(e.to_string()),
+        }
+    }
+}
+
+// Host errors display as their message; thrown values print readably.
+impl fmt::Display for MalErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ErrString(s) | ReaderError(s) | UnknownSymbol(s) | TypeError(s) | ArityError(s) => {
+                f.write_str(s)
+            }
+            Thrown(mv) => f.write_str(&mv.pr_str(true)),
+        }
+    }
+}
+
+impl fmt::Debug for MalErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ErrString(s) => write!(f, "ErrString({:?})", s),
+            ReaderError(s) => write!(f, "ReaderError({:?})", s),
+            UnknownSymbol(s) => write!(f, "UnknownSymbol({:?})", s),
+            TypeError(s) => write!(f, "TypeError({:?})", s),
+            ArityError(s) => write!(f, "ArityError({:?})", s),
+            Thrown(mv) => write!(f, "Thrown({})", mv.pr_str(true)),
+        }
     }
 }
 
+impl Error for MalErr {}
+
 pub fn atom(mv: &MalVal) -> MalVal {
     Atom(Rc::new(RefCell::new(mv.clone())))
 }
@@ -83,23 +174,25 @@ impl MalVal {
         match self {
             Str(s) if s.starts_with('\u{29e}') => Ok(Str(s.to_string())),
             Str(s) => Ok(Str(format!("\u{29e}{}", s))),
-            _ => error("invalid type for keyword"),
+            _ => type_error("invalid type for keyword"),
         }
     }
 
     pub fn empty_q(&self) -> MalRet {
         match self {
-            List(l, _) | Vector(l, _) => Ok(Bool(l.len() == 0)),
+            List(l, _) | Vector(l, _) => Ok(Bool(l.is_empty())),
+            Set(s, _) => Ok(Bool(s.is_empty())),
             Nil => Ok(Bool(true)),
-            _ => error("invalid type for empty?"),
+            _ => type_error("invalid type for empty?"),
         }
     }
 
     pub fn count(&self) -> MalRet {
         match self {
             List(l, _) | Vector(l, _) => Ok(Int(l.len() as i64)),
+            Set(s, _) => Ok(Int(s.len() as i64)),
             Nil => Ok(Int(0)),
-            _ => error("invalid type for count"),
+            _ => type_error("invalid type for count"),
         }
     }
 
@@ -113,13 +206,47 @@ impl MalVal {
                 ref params,
                 ..
             } => {
-                let fn_env = &env_bind(Some(env.clone()), params, args)?;
-                eval(ast, fn_env)
+                let (params, body) = fn_clause(params, ast, args.len())?;
+                let fn_env = &env_bind(Some(env.clone()), &params, args)?;
+                eval(&body, fn_env, &params)
             }
-            _ => error("attempt to call non-function"),
+            _ => type_error("attempt to call non-function"),
+        }
+    }
+
+    // Reference identity for atoms, functions and collections; values
+    // without identity (nil, booleans, numbers, strings and symbols) are
+    // identical when they are equal.
+    pub fn identical(&self, other: &MalVal) -> bool {
+        match (self, other) {
+            (Atom(a), Atom(b)) => Rc::ptr_eq(a, b),
+            (Func(f, m), Func(g, n)) => Rc::ptr_eq(f, g) && Rc::ptr_eq(m, n),
+            (
+                MalFunc {
+                    ast: a, env: e, meta: m, is_macro: x, ..
+                },
+                MalFunc {
+                    ast: b, env: f, meta: n, is_macro: y, ..
+                },
+            ) => Rc::ptr_eq(a, b) && Rc::ptr_eq(e, f) && Rc::ptr_eq(m, n) && x == y,
+            (List(a, m), List(b, n)) | (Vector(a, m), Vector(b, n)) => {
+                Rc::ptr_eq(a, b) && Rc::ptr_eq(m, n)
+            }
+            (Hash(a, m), Hash(b, n)) => Rc::ptr_eq(a, b) && Rc::ptr_eq(m, n),
+            (Set(a, m), Set(b, n)) => Rc::ptr_eq(a, b) && Rc::ptr_eq(m, n),
+            (Pattern(a), Pattern(b)) => Rc::ptr_eq(a, b),
+            (Nil, _) | (Bool(_), _) | (Int(_), _) | (Float(_), _) | (Str(_), _) | (Sym(_), _) => {
+                self == other
+            }
+            _ => false,
         }
     }
 
+    // Only nil and false are falsy.
+    pub fn truthy(&self) -> bool {
+        !matches!(self, Nil | Bool(false))
+    }
+
     pub fn keyword_q(&self) -> bool {
         matches!(self, Str(s) if s.starts_with('\u{29e}'))
     }
@@ -127,7 +254,7 @@ impl MalVal {
     pub fn deref(&self) -> MalRet {
         match self {
             Atom(a) => Ok(a.borrow().clone()),
-            _ => error("attempt to deref a non-Atom"),
+            _ => type_error("attempt to deref a non-Atom"),
         }
     }
 
@@ -137,7 +264,7 @@ impl MalVal {
                 *a.borrow_mut() = new.clone();
                 Ok(new.clone())
             }
-            _ => error("attempt to reset! a non-Atom"),
+            _ => type_error("attempt to reset! a non-Atom"),
         }
     }
 
@@ -150,16 +277,30 @@ impl MalVal {
                 *a.borrow_mut() = f.apply(fargs)?;
                 Ok(a.borrow().clone())
             }
-            _ => error("attempt to swap! a non-Atom"),
+            _ => type_error("attempt to swap! a non-Atom"),
+        }
+    }
+
+    pub fn compare_and_set_bang(&self, old: &MalVal, new: &MalVal) -> MalRet {
+        match self {
+            Atom(a) => {
+                // Release the shared borrow before taking the mutable one.
+                let matches = *a.borrow() == *old;
+                if matches {
+                    *a.borrow_mut() = new.clone();
+                }
+                Ok(Bool(matches))
+            }
+            _ => type_error("attempt to compare-and-set! a non-Atom"),
         }
     }
 
     pub fn get_meta(&self) -> MalRet {
         match self {
-            List(_, meta) | Vector(_, meta) | Hash(_, meta) => Ok((**meta).clone()),
+            List(_, meta) | Vector(_, meta) | Hash(_, meta) | Set(_, meta) => Ok((**meta).clone()),
             Func(_, meta) => Ok((**meta).clone()),
             MalFunc { meta, .. } => Ok((**meta).clone()),
-            _ => error("meta not supported by type"),
+            _ => type_error("meta not supported by type"),
         }
     }
 
@@ -168,11 +309,12 @@ impl MalVal {
             List(_, ref mut meta)
             | Vector(_, ref mut meta)
             | Hash(_, ref mut meta)
+            | Set(_, ref mut meta)
             | Func(_, ref mut meta)
             | MalFunc { ref mut meta, .. } => {
                 *meta = Rc::new(new_meta.clone());
             }
-            _ => return error("with-meta not supported by type"),
+            _ => return type_error("with-meta not supported by type"),
         };
         Ok(self.clone())
     }
@@ -184,6 +326,8 @@ impl PartialEq for MalVal {
             (Nil, Nil) => true,
             (Bool(ref a), Bool(ref b)) => a == b,
             (Int(ref a), Int(ref b)) => a == b,
+            // An integer never equals a float, even one with the same value.
+            (Float(ref a), Float(ref b)) => a == b,
             (Str(ref a), Str(ref b)) => a == b,
             (Sym(ref a), Sym(ref b)) => a == b,
             (List(ref a, _), List(ref b, _))
@@ -191,44 +335,187 @@ impl PartialEq for MalVal {
             | (List(ref a, _), Vector(ref b, _))
             | (Vector(ref a, _), List(ref b, _)) => a == b,
             (Hash(ref a, _), Hash(ref b, _)) => a == b,
+            (Set(ref a, _), Set(ref b, _)) => a == b,
+            // Patterns are equal when they are written the same way.
+            (Pattern(ref a), Pattern(ref b)) => a.as_str() == b.as_str(),
             (MalFunc { .. }, MalFunc { .. }) => false,
             _ => false,
         }
     }
 }
 
-pub fn func(f: fn(MalArgs) -> MalRet) -> MalVal {
-    Func(f, Rc::new(Nil))
+// Consistent with eq: a list and a vector with the same elements hash
+// alike, metadata is ignored, and the entries of a map or set are
+// combined without regard to their order.
+impl std::hash::Hash for MalVal {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match self {
+            Nil => state.write_u8(0),
+            Bool(b) => {
+                state.write_u8(1);
+                b.hash(state);
+            }
+            Int(i) => {
+                state.write_u8(2);
+                i.hash(state);
+            }
+            // 0.0 and -0.0 are equal, so they must hash alike.
+            Float(f) => {
+                state.write_u8(10);
+                (f + 0.0).to_bits().hash(state);
+            }
+            Str(s) => {
+                state.write_u8(3);
+                s.hash(state);
+            }
+            Sym(s) => {
+                state.write_u8(4);
+                s.hash(state);
+            }
+            List(v, _) | Vector(v, _) => {
+                state.write_u8(5);
+                v.hash(state);
+            }
+            Hash(hm, _) => {
+                state.write_u8(6);
+                hash_unordered(hm.iter(), state);
+            }
+            Set(s, _) => {
+                state.write_u8(8);
+                hash_unordered(s.iter(), state);
+            }
+            Pattern(re) => {
+                state.write_u8(9);
+                re.as_str().hash(state);
+            }
+            // Never equal to anything, so any hash is consistent.
+            Func(_, _) | MalFunc { .. } | Atom(_) => state.write_u8(7),
+        }
+    }
 }
 
-pub fn _assoc(mut hm: FnvHashMap<String, MalVal>, kvs: MalArgs) -> MalRet {
-    if kvs.len() % 2 != 0 {
-        return error("odd number of elements");
+fn hash_unordered<T, H>(items: impl ExactSizeIterator<Item = T>, state: &mut H)
+where
+    T: std::hash::Hash,
+    H: Hasher,
+{
+    state.write_usize(items.len());
+    let mut sum: u64 = 0;
+    for item in items {
+        let mut h = FnvHasher::default();
+        item.hash(&mut h);
+        sum = sum.wrapping_add(h.finish());
     }
-    for (k, v) in kvs.iter().tuples() {
-        match k {
-            Str(s) => {
-                hm.insert(s.to_string(), v.clone());
+    state.write_u64(sum);
+}
+
+// Not reflexive for functions, atoms and NaN, which equal nothing, not
+// even themselves. That is only sound because check_key and
+// check_hashable keep them out of maps and sets; do not remove those
+// checks.
+impl Eq for MalVal {}
+
+// Functions, atoms and NaN have no value to compare by, so they cannot
+// be hash-map keys or set elements, alone or inside a collection.
+fn check_hashable(k: &MalVal, what: &str) -> Result<(), MalErr> {
+    match k {
+        Func(_, _) | MalFunc { .. } => {
+            Err(TypeError(format!("a function cannot be {}: {}", what, k.pr_str(true))))
+        }
+        Atom(_) => Err(TypeError(format!("an atom cannot be {}: {}", what, k.pr_str(true)))),
+        Float(f) if f.is_nan() => Err(TypeError(format!("NaN cannot be {}", what))),
+        List(v, _) | Vector(v, _) => v.iter().try_for_each(|k| check_hashable(k, what)),
+        Hash(hm, _) => hm
+            .iter()
+            .try_for_each(|(k, v)| check_hashable(k, what).and(check_hashable(v, what))),
+        Set(s, _) => s.iter().try_for_each(|k| check_hashable(k, what)),
+        _ => Ok(()),
+    }
+}
+
+pub fn check_key(k: &MalVal) -> Result<(), MalErr> {
+    check_hashable(k, "a hash-map key")
+}
+
+// The parameters and body a function runs with for nargs arguments.
+// A multi-arity function has Nil params and a list of (params body)
+// clauses as its ast; an exact fixed arity is preferred over a
+// variadic clause.
+pub fn fn_clause(params: &MalVal, ast: &MalVal, nargs: usize) -> Result<(MalVal, MalVal), MalErr> {
+    let clauses = match (params, ast) {
+        (Nil, List(clauses, _)) => clauses,
+        _ => return Ok((params.clone(), ast.clone())),
+    };
+    let mut variadic = None;
+    for clause in clauses.iter() {
+        if let List(c, _) = clause {
+            if let List(ps, _) | Vector(ps, _) = &c[0] {
+                if ps.iter().any(|p| matches!(p, Sym(s) if s == "&")) {
+                    if variadic.is_none() && nargs + 2 >= ps.len() {
+                        variadic = Some((c[0].clone(), c[1].clone()));
+                    }
+                } else if ps.len() == nargs {
+                    return Ok((c[0].clone(), c[1].clone()));
+                }
             }
-            _ => return error("key is not string"),
         }
     }
+    match variadic {
+        Some(clause) => Ok(clause),
+        None => Err(ArityError(format!("no matching arity for {} arguments", nargs))),
+    }
+}
+
+// Builtins may be closures, so a host program embedding mal can
+// register functions that capture its own state.
+pub fn func<F: Fn(MalArgs) -> MalRet + 'static>(f: F) -> MalVal {
+    Func(Rc::new(f), Rc::new(Nil))
+}
+
+pub fn _assoc(mut hm: MalMap, kvs: MalArgs) -> MalRet {
+    if !kvs.len().is_multiple_of(2) {
+        return arity_error("odd number of elements");
+    }
+    for (k, v) in kvs.iter().tuples() {
+        check_key(k)?;
+        hm.insert(k.clone(), v.clone());
+    }
     Ok(Hash(Rc::new(hm), Rc::new(Nil)))
 }
 
-pub fn _dissoc(mut hm: FnvHashMap<String, MalVal>, ks: MalArgs) -> MalRet {
+pub fn _dissoc(mut hm: MalMap, ks: MalArgs) -> MalRet {
     for k in ks {
-        match k {
-            Str(ref s) => {
-                let _ = hm.remove(s);
-            }
-            _ => return error("key is not string"),
-        }
+        let _ = hm.shift_remove(&k);
     }
     Ok(Hash(Rc::new(hm), Rc::new(Nil)))
 }
 
 pub fn hash_map(kvs: MalArgs) -> MalRet {
-    let hm: FnvHashMap<String, MalVal> = FnvHashMap::default();
+    let hm = MalMap::default();
     _assoc(hm, kvs)
 }
+
+pub fn _conj_set(mut s: MalSet, items: &[MalVal]) -> MalRet {
+    for item in items {
+        check_hashable(item, "a set element")?;
+        s.insert(item.clone());
+    }
+    Ok(Set(Rc::new(s), Rc::new(Nil)))
+}
+
+pub fn hash_set(items: MalArgs) -> MalRet {
+    _conj_set(MalSet::default(), &items)
+}
+
+pub fn pattern(s: &str) -> MalRet {
+    match Regex::new(s) {
+        Ok(re) => Ok(Pattern(Rc::new(re))),
+        // A syntax error is shown over several lines with the pattern
+        // and a caret; the last line says what is wrong.
+        Err(e) => {
+            let msg = e.to_string();
+            let last = msg.lines().last().unwrap_or("");
+            error(&format!("invalid regular expression: {}", last.trim_start_matches("error: ")))
+        }
+    }
+}